@@ -236,12 +236,23 @@ if (!nativeBinding) {
   throw new Error(`Failed to load native binding`)
 }
 
-const { HttpFileDownloader, proxyCheckHttp, proxyCheckSocks5H, proxyCheckSocks5, sqliteAddLoginPassword, createSqliteLoginDatabase, archivateFolder, killProcessByPid, closeProcessByPid, setForegroundByPid, rustLoggerInit } = nativeBinding
+const { HttpFileDownloader, HttpFileUploader, DownloadManager, ErrorCode, ProxyStage, setGlobalRateLimit, setProxyCheckEndpoints, proxyCheck, proxyCheckHttp, proxyCheckSocks5H, proxyCheckSocks5, checkProxies, ProxyScheme, parseProxy, parseProxyList, sqliteAddLoginPassword, createSqliteLoginDatabase, archivateFolder, killProcessByPid, closeProcessByPid, setForegroundByPid, rustLoggerInit } = nativeBinding
 
 module.exports.HttpFileDownloader = HttpFileDownloader
+module.exports.HttpFileUploader = HttpFileUploader
+module.exports.DownloadManager = DownloadManager
+module.exports.ErrorCode = ErrorCode
+module.exports.ProxyStage = ProxyStage
+module.exports.setGlobalRateLimit = setGlobalRateLimit
+module.exports.setProxyCheckEndpoints = setProxyCheckEndpoints
+module.exports.proxyCheck = proxyCheck
 module.exports.proxyCheckHttp = proxyCheckHttp
 module.exports.proxyCheckSocks5H = proxyCheckSocks5H
 module.exports.proxyCheckSocks5 = proxyCheckSocks5
+module.exports.checkProxies = checkProxies
+module.exports.ProxyScheme = ProxyScheme
+module.exports.parseProxy = parseProxy
+module.exports.parseProxyList = parseProxyList
 module.exports.sqliteAddLoginPassword = sqliteAddLoginPassword
 module.exports.createSqliteLoginDatabase = createSqliteLoginDatabase
 module.exports.archivateFolder = archivateFolder
@@ -0,0 +1,92 @@
+use crate::error::Error;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+/// Incremental hasher for the digest algorithms accepted from JS
+pub enum Checksum {
+  Sha256(Sha256),
+  Sha1(Sha1),
+  Md5(Md5),
+}
+
+impl Checksum {
+  /// Creates a hasher from an algorithm name, `sha256` is used when none is given
+  pub fn from_algorithm(algorithm: Option<&str>) -> Result<Self, Error> {
+    match algorithm
+      .map(|a| a.to_ascii_lowercase().replace('-', ""))
+      .as_deref()
+    {
+      None | Some("sha256") => Ok(Checksum::Sha256(Sha256::new())),
+      Some("sha1") => Ok(Checksum::Sha1(Sha1::new())),
+      Some("md5") => Ok(Checksum::Md5(Md5::new())),
+      Some(other) => Err(Error::InvalidArgument(format!(
+        "Unsupported checksum algorithm: {}",
+        other
+      ))),
+    }
+  }
+
+  pub fn update(&mut self, data: &[u8]) {
+    match self {
+      Checksum::Sha256(h) => h.update(data),
+      Checksum::Sha1(h) => h.update(data),
+      Checksum::Md5(h) => h.update(data),
+    }
+  }
+
+  /// Returns the lowercase hex digest
+  pub fn finalize(self) -> String {
+    match self {
+      Checksum::Sha256(h) => hex::encode(h.finalize()),
+      Checksum::Sha1(h) => hex::encode(h.finalize()),
+      Checksum::Md5(h) => hex::encode(h.finalize()),
+    }
+  }
+
+  /// Feeds the first `len` bytes of the file at `path` into the hasher
+  pub async fn update_from_file(&mut self, path: &Path, len: u64) -> Result<(), Error> {
+    let mut file = File::open(path).await.map_err(|e| Error::io(e, path))?;
+    let mut buf = vec![0; 65536];
+    let mut left = len;
+    while left > 0 {
+      let want = left.min(buf.len() as u64) as usize;
+      let n = file.read(&mut buf[..want]).await?;
+      if n == 0 {
+        break;
+      }
+      self.update(&buf[..n]);
+      left -= n as u64;
+    }
+    Ok(())
+  }
+}
+
+/// Compares a computed hex digest with the expected one, ignoring case
+pub fn digest_matches(computed: &str, expected: &str) -> bool {
+  computed.eq_ignore_ascii_case(expected.trim())
+}
+
+#[test]
+fn test_checksum_algorithms() {
+  let digest = |algorithm| {
+    let mut checksum = Checksum::from_algorithm(algorithm).unwrap();
+    checksum.update(b"hello ");
+    checksum.update(b"world");
+    checksum.finalize()
+  };
+  assert_eq!(
+    digest(None),
+    "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+  );
+  assert_eq!(
+    digest(Some("SHA-1")),
+    "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed"
+  );
+  assert_eq!(digest(Some("md5")), "5eb63bbbe01eeed093cb22bb8f5acdc3");
+  assert!(Checksum::from_algorithm(Some("crc32")).is_err());
+  assert!(digest_matches("abcdef", " ABCDEF"));
+}
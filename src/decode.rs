@@ -0,0 +1,121 @@
+use crate::error::Error;
+use brotli_decompressor::DecompressorWriter;
+use flate2::write::{GzDecoder, ZlibDecoder};
+use reqwest::header::{HeaderMap, CONTENT_ENCODING};
+use std::io::{self, Write};
+
+/// Size of the brotli window buffer
+const BROTLI_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Decodes a response body sent with a `Content-Encoding`, chunk by chunk as it arrives
+pub enum Decoder {
+  Gzip(GzDecoder<Vec<u8>>),
+  /// `deflate` is the zlib format, see RFC 9110
+  Deflate(ZlibDecoder<Vec<u8>>),
+  Brotli(Box<DecompressorWriter<Vec<u8>>>),
+}
+
+impl Decoder {
+  /// Returns the decoder for the `Content-Encoding` in `headers`, `None` when the body is not encoded
+  pub fn from_headers(headers: &HeaderMap, url: &str) -> Result<Option<Self>, Error> {
+    let encoding = match headers.get(CONTENT_ENCODING) {
+      Some(value) => value
+        .to_str()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase(),
+      None => return Ok(None),
+    };
+    match encoding.as_str() {
+      "" | "identity" => Ok(None),
+      "gzip" | "x-gzip" => Ok(Some(Decoder::Gzip(GzDecoder::new(Vec::new())))),
+      "deflate" => Ok(Some(Decoder::Deflate(ZlibDecoder::new(Vec::new())))),
+      "br" => Ok(Some(Decoder::Brotli(Box::new(DecompressorWriter::new(
+        Vec::new(),
+        BROTLI_BUFFER_SIZE,
+      ))))),
+      _ => Err(Error::UnsupportedEncoding {
+        url: url.to_string(),
+        encoding,
+      }),
+    }
+  }
+
+  /// Feeds `chunk` of the encoded body and returns the bytes decoded so far
+  pub fn decode(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+    let output = match self {
+      Decoder::Gzip(decoder) => {
+        decoder.write_all(chunk)?;
+        decoder.flush()?;
+        decoder.get_mut()
+      }
+      Decoder::Deflate(decoder) => {
+        decoder.write_all(chunk)?;
+        decoder.flush()?;
+        decoder.get_mut()
+      }
+      Decoder::Brotli(decoder) => {
+        decoder.write_all(chunk)?;
+        decoder.flush()?;
+        decoder.get_mut()
+      }
+    };
+    Ok(std::mem::take(output))
+  }
+
+  /// Returns the bytes still buffered once the whole body was fed in
+  pub fn finish(&mut self) -> io::Result<Vec<u8>> {
+    let output = match self {
+      Decoder::Gzip(decoder) => {
+        decoder.try_finish()?;
+        decoder.get_mut()
+      }
+      Decoder::Deflate(decoder) => {
+        decoder.try_finish()?;
+        decoder.get_mut()
+      }
+      Decoder::Brotli(decoder) => {
+        decoder.flush()?;
+        decoder.get_mut()
+      }
+    };
+    Ok(std::mem::take(output))
+  }
+}
+
+#[test]
+fn test_decoder() {
+  use flate2::write::{GzEncoder, ZlibEncoder};
+  use flate2::Compression;
+
+  let data = b"hello world ".repeat(1000);
+  let mut headers = HeaderMap::new();
+  let mut decode = |encoding: &str, encoded: Vec<u8>| {
+    headers.insert(CONTENT_ENCODING, encoding.parse().unwrap());
+    let mut decoder = Decoder::from_headers(&headers, "").unwrap().unwrap();
+    let mut decoded = Vec::new();
+    for chunk in encoded.chunks(100) {
+      decoded.extend(decoder.decode(chunk).unwrap());
+    }
+    decoded.extend(decoder.finish().unwrap());
+    decoded
+  };
+
+  let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+  encoder.write_all(&data).unwrap();
+  assert_eq!(decode("gzip", encoder.finish().unwrap()), data);
+
+  let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+  encoder.write_all(&data).unwrap();
+  assert_eq!(decode("deflate", encoder.finish().unwrap()), data);
+
+  assert_eq!(decode("br", crate::http::brotli_stored(&data)), data);
+
+  headers.insert(CONTENT_ENCODING, "zstd".parse().unwrap());
+  assert!(matches!(
+    Decoder::from_headers(&headers, ""),
+    Err(Error::UnsupportedEncoding { .. })
+  ));
+  headers.insert(CONTENT_ENCODING, "identity".parse().unwrap());
+  assert!(Decoder::from_headers(&headers, "").unwrap().is_none());
+}
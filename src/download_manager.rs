@@ -0,0 +1,562 @@
+use crate::error::{ErrorCode, ErrorInfo};
+use crate::http::{
+  build_client, read_timeout, DownloadOptions, DownloadProgress, HttpClientOptions,
+  HttpFileDownloader, ProgressSink,
+};
+use crate::throttle::RateLimiter;
+use napi::threadsafe_function::{
+  ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
+};
+use napi::{Env, JsFunction};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[napi(object)]
+pub struct DownloadJob {
+  pub url: String,
+  pub destination: String,
+  /// Jobs with a higher priority start first, defaults to 0
+  pub priority: Option<i32>,
+  /// Expected hex digest of the complete file
+  pub checksum: Option<String>,
+  /// Digest algorithm for `checksum`: `sha256` (default), `sha1` or `md5`
+  pub checksum_algorithm: Option<String>,
+  /// Urls tried in order when `url` fails
+  pub mirrors: Option<Vec<String>>,
+  /// Store the body without decoding its `Content-Encoding`
+  pub keep_encoding: Option<bool>,
+}
+
+#[napi(object)]
+pub struct DownloadJobState {
+  pub id: u32,
+  pub url: String,
+  pub destination: String,
+  pub priority: i32,
+  /// `queued` or `running`
+  pub state: &'static str,
+}
+
+/// Event passed to the manager callback as `(target, event)`
+#[napi(object)]
+#[derive(Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadJobEvent {
+  /// `queued`, `started`, `reprioritised`, `removed`, `completed`, `failed`
+  /// or one of the `HttpFileDownloader` event targets. Every started job ends
+  /// with `completed`, `failed` or `removed`.
+  pub target: &'static str,
+  pub job_id: u32,
+  pub downloaded: Option<i64>,
+  pub total: Option<i64>,
+  pub attempt: Option<u32>,
+  pub reason: Option<String>,
+  pub rate: Option<f64>,
+  pub average_rate: Option<f64>,
+  pub eta_ms: Option<i64>,
+  pub elapsed_ms: Option<i64>,
+  pub decoded: Option<i64>,
+  /// Outcome of a finished job, set on `completed`, `failed` and `removed` events
+  pub status: Option<bool>,
+  /// Why the job failed, set on `failed` and `removed` events of started jobs
+  pub error: Option<ErrorInfo>,
+  pub digest: Option<String>,
+  /// Mirror in use, set on `mirror` and `completed` events
+  pub url: Option<String>,
+  /// Error message, set on `failed` events
+  pub message: Option<String>,
+  /// Number of jobs waiting in the queue after this event
+  pub queued: u32,
+  /// Number of jobs running after this event
+  pub running: u32,
+}
+
+/// Receives the events of all jobs of a manager
+type JobSink = Arc<dyn Fn(DownloadJobEvent) + Send + Sync>;
+
+struct Job {
+  id: u32,
+  url: String,
+  destination: String,
+  priority: i32,
+  options: DownloadOptions,
+}
+
+#[derive(Default)]
+struct Queue {
+  next_id: u32,
+  queued: Vec<Job>,
+  running: HashMap<u32, (Job, Arc<HttpFileDownloader>)>,
+}
+
+impl Queue {
+  /// Removes the queued job with the highest priority, oldest first on ties
+  fn pop(&mut self) -> Option<Job> {
+    let index = self
+      .queued
+      .iter()
+      .enumerate()
+      .max_by(|(_, a), (_, b)| a.priority.cmp(&b.priority).then(b.id.cmp(&a.id)))
+      .map(|(index, _)| index)?;
+    Some(self.queued.remove(index))
+  }
+}
+
+struct Manager {
+  concurrency: usize,
+  client: reqwest::Client,
+  read_timeout: Option<Duration>,
+  /// Budget shared by all jobs of this manager
+  rate_limiter: Arc<RateLimiter>,
+  emitter: Option<JobSink>,
+  queue: Mutex<Queue>,
+}
+
+impl Manager {
+  /// Sends `event` with the current queue counters filled in.
+  /// Must not be called while `queue` is locked.
+  fn emit(&self, mut event: DownloadJobEvent) {
+    if let Some(emit) = &self.emitter {
+      let queue = self.queue.lock().unwrap();
+      event.queued = queue.queued.len() as u32;
+      event.running = queue.running.len() as u32;
+      drop(queue);
+      emit(event);
+    }
+  }
+
+  /// Starts queued jobs until the concurrency limit is reached
+  fn schedule(manager: &Arc<Manager>) {
+    let mut started = Vec::new();
+    {
+      let mut queue = manager.queue.lock().unwrap();
+      while queue.running.len() < manager.concurrency {
+        let job = match queue.pop() {
+          Some(job) => job,
+          None => break,
+        };
+        let downloader = Arc::new(HttpFileDownloader::with_client(
+          Some(Manager::job_sink(manager, job.id)),
+          manager.client.clone(),
+          manager.read_timeout,
+          Some(manager.rate_limiter.clone()),
+        ));
+        started.push((
+          job.id,
+          job.url.clone(),
+          job.destination.clone(),
+          job.options.clone(),
+          downloader.clone(),
+        ));
+        queue.running.insert(job.id, (job, downloader));
+      }
+    }
+
+    for (id, url, destination, options, downloader) in started {
+      info!("[Manager] Starting job {}: {}", id, url);
+      manager.emit(DownloadJobEvent {
+        target: "started",
+        job_id: id,
+        ..Default::default()
+      });
+      let manager = manager.clone();
+      napi::bindgen_prelude::spawn(async move {
+        // Not `download_file`, which would reset a cancel issued by `remove_job`
+        let response = downloader.download(url, destination, Some(options)).await;
+        manager.queue.lock().unwrap().running.remove(&id);
+        // Only `remove_job` cancels the downloader of a job
+        let target = match &response.error {
+          None if response.status => "completed",
+          Some(error) if error.code == ErrorCode::Cancelled => "removed",
+          _ => {
+            error!("[Manager] Job {} failed", id);
+            "failed"
+          }
+        };
+        let event = DownloadJobEvent {
+          target,
+          job_id: id,
+          status: Some(response.status),
+          message: response.error.as_ref().map(|error| error.message.clone()),
+          error: response.error,
+          digest: response.digest,
+          url: response.url,
+          ..Default::default()
+        };
+        manager.emit(event);
+        Manager::schedule(&manager);
+      });
+    }
+  }
+
+  /// Forwards downloader events of job `id` to the manager callback
+  fn job_sink(manager: &Arc<Manager>, id: u32) -> ProgressSink {
+    let manager = Arc::downgrade(manager);
+    Arc::new(move |progress: DownloadProgress| {
+      if let Some(manager) = manager.upgrade() {
+        manager.emit(DownloadJobEvent {
+          target: progress.target,
+          job_id: id,
+          downloaded: Some(progress.downloaded),
+          total: progress.total,
+          attempt: progress.attempt,
+          reason: progress.reason,
+          rate: progress.rate,
+          average_rate: progress.average_rate,
+          eta_ms: progress.eta_ms,
+          elapsed_ms: progress.elapsed_ms,
+          decoded: progress.decoded,
+          url: progress.url,
+          ..Default::default()
+        });
+      }
+    })
+  }
+}
+
+/// Runs download jobs by priority with a global concurrency limit
+#[napi]
+pub struct DownloadManager {
+  manager: Arc<Manager>,
+}
+
+impl DownloadManager {
+  /// Creates a manager whose jobs share `client` and report to `emitter`
+  fn with_client(
+    concurrency: u32,
+    emitter: Option<JobSink>,
+    client: reqwest::Client,
+    read_timeout: Option<Duration>,
+  ) -> Self {
+    Self {
+      manager: Arc::new(Manager {
+        concurrency: concurrency.max(1) as usize,
+        client,
+        read_timeout,
+        rate_limiter: Arc::new(RateLimiter::new()),
+        emitter,
+        queue: Mutex::new(Queue::default()),
+      }),
+    }
+  }
+}
+
+#[allow(unused)]
+#[napi]
+impl DownloadManager {
+  #[napi(constructor)]
+  pub fn new(
+    env: Env,
+    concurrency: u32,
+    emitter: Option<JsFunction>,
+    options: Option<HttpClientOptions>,
+  ) -> napi::Result<Self> {
+    let options = options.unwrap_or_default();
+    let emitter: Option<JobSink> = match emitter {
+      Some(func) => {
+        let tsfn: ThreadsafeFunction<_, ErrorStrategy::Fatal> =
+          func.create_threadsafe_function(0, |ctx: ThreadSafeCallContext<DownloadJobEvent>| {
+            Ok(vec![
+              ctx.env.create_string(ctx.value.target)?.into_unknown(),
+              ctx.env.to_js_value(&ctx.value)?.into_unknown(),
+            ])
+          })?;
+        Some(Arc::new(move |event| {
+          tsfn.call(event, ThreadsafeFunctionCallMode::NonBlocking);
+        }))
+      }
+      None => None,
+    };
+
+    Ok(Self::with_client(
+      concurrency,
+      emitter,
+      build_client(&options).map_err(|e| e.into_js_error(env))?,
+      read_timeout(&options),
+    ))
+  }
+
+  /// Limits the combined throughput of this manager's jobs, `0` removes the limit
+  #[napi]
+  pub fn set_rate_limit(&self, bytes_per_second: i64) {
+    info!("[Manager] Rate limit: {} B/s", bytes_per_second);
+    self
+      .manager
+      .rate_limiter
+      .set_limit(bytes_per_second.max(0) as u64);
+  }
+
+  /// Queues a job and returns its id
+  #[napi]
+  pub fn add_job(&self, job: DownloadJob) -> u32 {
+    let id = {
+      let mut queue = self.manager.queue.lock().unwrap();
+      queue.next_id += 1;
+      let id = queue.next_id;
+      queue.queued.push(Job {
+        id,
+        url: job.url,
+        destination: job.destination,
+        priority: job.priority.unwrap_or(0),
+        options: DownloadOptions {
+          checksum: job.checksum,
+          checksum_algorithm: job.checksum_algorithm,
+          mirrors: job.mirrors,
+          keep_encoding: job.keep_encoding,
+          ..Default::default()
+        },
+      });
+      id
+    };
+    self.manager.emit(DownloadJobEvent {
+      target: "queued",
+      job_id: id,
+      ..Default::default()
+    });
+    Manager::schedule(&self.manager);
+    id
+  }
+
+  /// Removes a queued job, or cancels it if it is already running.
+  /// Either way a `removed` event follows, for a running job once it has stopped.
+  #[napi]
+  pub fn remove_job(&self, id: u32) -> bool {
+    let mut queue = self.manager.queue.lock().unwrap();
+    if let Some(index) = queue.queued.iter().position(|job| job.id == id) {
+      queue.queued.remove(index);
+      drop(queue);
+      self.manager.emit(DownloadJobEvent {
+        target: "removed",
+        job_id: id,
+        ..Default::default()
+      });
+      return true;
+    }
+    match queue.running.get(&id) {
+      Some((_, downloader)) => {
+        downloader.cancel();
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Changes the priority of a queued job
+  #[napi]
+  pub fn set_priority(&self, id: u32, priority: i32) -> bool {
+    let found = {
+      let mut queue = self.manager.queue.lock().unwrap();
+      match queue.queued.iter_mut().find(|job| job.id == id) {
+        Some(job) => {
+          job.priority = priority;
+          true
+        }
+        None => false,
+      }
+    };
+    if found {
+      self.manager.emit(DownloadJobEvent {
+        target: "reprioritised",
+        job_id: id,
+        ..Default::default()
+      });
+    }
+    found
+  }
+
+  /// Snapshot of running and queued jobs, in the order they will run
+  #[napi]
+  pub fn jobs(&self) -> Vec<DownloadJobState> {
+    let queue = self.manager.queue.lock().unwrap();
+    let state = |job: &Job, state| DownloadJobState {
+      id: job.id,
+      url: job.url.clone(),
+      destination: job.destination.clone(),
+      priority: job.priority,
+      state,
+    };
+
+    let mut running: Vec<_> = queue.running.values().map(|(job, _)| job).collect();
+    running.sort_by_key(|job| job.id);
+    let mut queued: Vec<_> = queue.queued.iter().collect();
+    queued.sort_by(|a, b| b.priority.cmp(&a.priority).then(a.id.cmp(&b.id)));
+
+    running
+      .into_iter()
+      .map(|job| state(job, "running"))
+      .chain(queued.into_iter().map(|job| state(job, "queued")))
+      .collect()
+  }
+}
+
+#[test]
+fn test_queue_pop_order() {
+  let job = |id, priority| Job {
+    id,
+    url: String::new(),
+    destination: String::new(),
+    priority,
+    options: DownloadOptions::default(),
+  };
+  let mut queue = Queue {
+    queued: vec![job(1, 0), job(2, 5), job(3, 5), job(4, -1)],
+    ..Default::default()
+  };
+  let order: Vec<u32> = std::iter::from_fn(|| queue.pop().map(|job| job.id)).collect();
+  assert_eq!(order, vec![2, 3, 1, 4]);
+}
+
+#[cfg(test)]
+fn test_manager(concurrency: u32) -> (DownloadManager, Arc<Mutex<Vec<DownloadJobEvent>>>) {
+  let events = Arc::new(Mutex::new(Vec::new()));
+  let sink = events.clone();
+  let client = reqwest::Client::builder().no_proxy().build().unwrap();
+  let manager = DownloadManager::with_client(
+    concurrency,
+    Some(Arc::new(move |event| sink.lock().unwrap().push(event))),
+    client,
+    None,
+  );
+  (manager, events)
+}
+
+/// Waits until `count` jobs have ended and returns all events
+#[cfg(test)]
+async fn wait_for_jobs(
+  events: &Arc<Mutex<Vec<DownloadJobEvent>>>,
+  count: usize,
+) -> Vec<DownloadJobEvent> {
+  let ended = |event: &DownloadJobEvent| event.status.is_some() || event.target == "removed";
+  let wait = async {
+    loop {
+      let events = events.lock().unwrap().clone();
+      if events.iter().filter(|event| ended(event)).count() >= count {
+        return events;
+      }
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+  };
+  tokio::time::timeout(Duration::from_secs(10), wait)
+    .await
+    .unwrap()
+}
+
+#[cfg(test)]
+fn job(url: String, destination: &std::path::Path) -> DownloadJob {
+  DownloadJob {
+    url,
+    destination: destination.display().to_string(),
+    priority: None,
+    checksum: None,
+    checksum_algorithm: None,
+    mirrors: None,
+    keep_encoding: None,
+  }
+}
+
+#[tokio::test]
+async fn test_manager_priorities() {
+  use crate::http::test_dir;
+  use crate::mock_server::{test_data, MockServer, Route};
+
+  let server = MockServer::start().await;
+  let dir = test_dir("manager-priorities");
+  let data = test_data(20_000, 1);
+  for path in ["/a", "/b", "/c", "/d"] {
+    server.route(path, Route::new(&data));
+  }
+  let (manager, events) = test_manager(1);
+  // Keeps the first job running while the others are queued
+  manager.set_rate_limit(100_000);
+
+  let ids: Vec<u32> = ["a", "b", "c", "d"]
+    .iter()
+    .map(|name| manager.add_job(job(server.url(&format!("/{}", name)), &dir.join(name))))
+    .collect();
+  assert!(manager.set_priority(ids[2], 10));
+  assert!(!manager.set_priority(ids[0], 10));
+  assert!(manager.remove_job(ids[3]));
+  assert!(!manager.remove_job(99));
+  let states: Vec<_> = manager
+    .jobs()
+    .iter()
+    .map(|job| (job.id, job.state))
+    .collect();
+  assert_eq!(
+    states,
+    vec![(ids[0], "running"), (ids[2], "queued"), (ids[1], "queued")]
+  );
+
+  let events = wait_for_jobs(&events, 4).await;
+  let started: Vec<u32> = events
+    .iter()
+    .filter(|event| event.target == "started")
+    .map(|event| event.job_id)
+    .collect();
+  assert_eq!(started, vec![ids[0], ids[2], ids[1]]);
+  assert!(events.iter().all(|event| event.running <= 1));
+
+  let targets = |id| {
+    events
+      .iter()
+      .filter(|event| event.job_id == id && event.target != "progress")
+      .map(|event| event.target)
+      .collect::<Vec<_>>()
+  };
+  assert_eq!(targets(ids[0]), vec!["queued", "started", "completed"]);
+  assert_eq!(
+    targets(ids[2]),
+    vec!["queued", "reprioritised", "started", "completed"]
+  );
+  assert_eq!(targets(ids[3]), vec!["queued", "removed"]);
+  assert_eq!(std::fs::read(dir.join("b")).unwrap(), data);
+  assert!(!dir.join("d").exists());
+  let last = events.last().unwrap();
+  assert_eq!((last.queued, last.running), (0, 0));
+}
+
+#[tokio::test]
+async fn test_manager_concurrency_and_failures() {
+  use crate::http::test_dir;
+  use crate::mock_server::{test_data, MockServer, Route};
+
+  let server = MockServer::start().await;
+  let dir = test_dir("manager-failures");
+  let data = test_data(20_000, 2);
+  server.route("/a", Route::new(&data));
+  server.route("/b", Route::new(&data));
+  let (manager, events) = test_manager(2);
+  manager.set_rate_limit(100_000);
+
+  let removed = manager.add_job(job(server.url("/a"), &dir.join("a")));
+  let missing = manager.add_job(job(server.url("/missing"), &dir.join("missing")));
+  let completed = manager.add_job(job(server.url("/b"), &dir.join("b")));
+  assert_eq!(manager.jobs()[2].state, "queued");
+  // The running job is cancelled before its download starts
+  assert!(manager.remove_job(removed));
+
+  let events = wait_for_jobs(&events, 3).await;
+  assert!(events.iter().all(|event| event.running <= 2));
+  assert!(events.iter().any(|event| event.running == 2));
+  let end = |id| {
+    events
+      .iter()
+      .find(|event| event.job_id == id && event.status.is_some())
+      .unwrap()
+  };
+
+  let event = end(removed);
+  assert_eq!(event.target, "removed");
+  assert_eq!(event.error.as_ref().unwrap().code, ErrorCode::Cancelled);
+  let event = end(missing);
+  assert_eq!(event.target, "failed");
+  assert_eq!(event.status, Some(false));
+  assert_eq!(event.error.as_ref().unwrap().code, ErrorCode::Http);
+  assert!(event.message.is_some());
+  let event = end(completed);
+  assert_eq!(event.target, "completed");
+  assert!(event.error.is_none());
+  assert_eq!(std::fs::read(dir.join("b")).unwrap(), data);
+}
@@ -0,0 +1,395 @@
+use napi::bindgen_prelude::ToNapiValue;
+use napi::{Env, JsObject};
+use reqwest::StatusCode;
+use serde::Serialize;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Machine readable kind of an `Error`, exported to JS as `code`
+#[napi(string_enum)]
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+  /// The server answered with a 4xx or 5xx status
+  Http,
+  /// The connection failed or broke off
+  Network,
+  Timeout,
+  Io,
+  /// The transfer ended before all bytes arrived
+  Incomplete,
+  ContentLengthUnsupported,
+  LengthMismatch,
+  DigestMismatch,
+  /// The file on the server changed since the partial download started
+  RemoteChanged,
+  InvalidArchive,
+  /// The response uses a `Content-Encoding` that can not be decoded
+  UnsupportedEncoding,
+  /// The response body is corrupt for its `Content-Encoding`
+  InvalidEncoding,
+  /// A manifest could not be parsed or lists unsafe paths
+  InvalidManifest,
+  Cancelled,
+  InvalidArgument,
+  Proxy,
+  Database,
+  Archive,
+}
+
+/// Step of a proxy check that failed, set on `Proxy` errors.
+/// Spelled in lowercase, as in the error messages.
+#[napi(string_enum = "lowercase")]
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyStage {
+  /// The proxy or check endpoint is malformed
+  Parse,
+  /// The proxy could not be reached or refused the tunnel
+  Connect,
+  /// The proxy rejected the credentials
+  Auth,
+  /// The TLS handshake with the proxy or the endpoint failed
+  Tls,
+  /// The response was missing, malformed or held no address
+  Read,
+}
+
+impl ProxyStage {
+  pub fn name(&self) -> &'static str {
+    match self {
+      ProxyStage::Parse => "parse",
+      ProxyStage::Connect => "connect",
+      ProxyStage::Auth => "auth",
+      ProxyStage::Tls => "tls",
+      ProxyStage::Read => "read",
+    }
+  }
+}
+
+/// Error details passed to JS, also set as properties of thrown and rejected errors
+#[napi(object)]
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorInfo {
+  pub code: ErrorCode,
+  pub message: String,
+  /// Status of the failed response, set for `Http`
+  pub http_status: Option<u32>,
+  /// `std::io::ErrorKind` of the failure, set for `Io`
+  pub io_kind: Option<String>,
+  pub path: Option<String>,
+  pub url: Option<String>,
+  /// Set for `Incomplete`, `LengthMismatch` and `DigestMismatch`
+  pub expected: Option<String>,
+  pub actual: Option<String>,
+  /// Set for `Proxy`
+  pub proxy_stage: Option<ProxyStage>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  #[error("{url} responded with {status}")]
+  Http { status: StatusCode, url: String },
+
+  #[error("request to {url} failed: {message}")]
+  Network { url: String, message: String },
+
+  #[error("timed out waiting for {url}")]
+  Timeout { url: String },
+
+  #[error("{message}{}", .path.as_ref().map(|p| format!(": {}", p.display())).unwrap_or_default())]
+  Io {
+    kind: io::ErrorKind,
+    path: Option<PathBuf>,
+    message: String,
+  },
+
+  #[error("connection closed after {actual} of {expected} bytes")]
+  Incomplete { expected: u64, actual: u64 },
+
+  #[error("{url} does not report a Content-Length")]
+  ContentLengthUnsupported { url: String },
+
+  #[error("expected {expected} bytes, got {actual}")]
+  LengthMismatch { expected: u64, actual: u64 },
+
+  #[error("digest {actual} does not match {expected}")]
+  DigestMismatch { expected: String, actual: String },
+
+  #[error("{url} changed since the download started")]
+  RemoteChanged { url: String },
+
+  #[error("{} is not a valid archive: {message}", .path.display())]
+  InvalidArchive { path: PathBuf, message: String },
+
+  #[error("{url} uses unsupported Content-Encoding {encoding}")]
+  UnsupportedEncoding { url: String, encoding: String },
+
+  #[error("failed to decode the body of {url}: {message}")]
+  InvalidEncoding { url: String, message: String },
+
+  #[error("invalid manifest {url}: {message}")]
+  InvalidManifest { url: String, message: String },
+
+  #[error("cancelled")]
+  Cancelled,
+
+  #[error("{0}")]
+  InvalidArgument(String),
+
+  #[error("proxy {} failed: {message}", .stage.name())]
+  Proxy { stage: ProxyStage, message: String },
+
+  #[error("{0}")]
+  Database(String),
+
+  #[error("{0}")]
+  Archive(String),
+}
+
+impl Error {
+  pub fn io(error: io::Error, path: &Path) -> Self {
+    Self::Io {
+      kind: error.kind(),
+      path: Some(path.to_path_buf()),
+      message: error.to_string(),
+    }
+  }
+
+  pub fn proxy(stage: ProxyStage, message: impl ToString) -> Self {
+    Self::Proxy {
+      stage,
+      message: message.to_string(),
+    }
+  }
+
+  pub fn code(&self) -> ErrorCode {
+    match self {
+      Error::Http { .. } => ErrorCode::Http,
+      Error::Network { .. } => ErrorCode::Network,
+      Error::Timeout { .. } => ErrorCode::Timeout,
+      Error::Io { .. } => ErrorCode::Io,
+      Error::Incomplete { .. } => ErrorCode::Incomplete,
+      Error::ContentLengthUnsupported { .. } => ErrorCode::ContentLengthUnsupported,
+      Error::LengthMismatch { .. } => ErrorCode::LengthMismatch,
+      Error::DigestMismatch { .. } => ErrorCode::DigestMismatch,
+      Error::RemoteChanged { .. } => ErrorCode::RemoteChanged,
+      Error::InvalidArchive { .. } => ErrorCode::InvalidArchive,
+      Error::UnsupportedEncoding { .. } => ErrorCode::UnsupportedEncoding,
+      Error::InvalidEncoding { .. } => ErrorCode::InvalidEncoding,
+      Error::InvalidManifest { .. } => ErrorCode::InvalidManifest,
+      Error::Cancelled => ErrorCode::Cancelled,
+      Error::InvalidArgument(_) => ErrorCode::InvalidArgument,
+      Error::Proxy { .. } => ErrorCode::Proxy,
+      Error::Database(_) => ErrorCode::Database,
+      Error::Archive(_) => ErrorCode::Archive,
+    }
+  }
+
+  /// Whether retrying the same request may succeed: connection failures, 408, 429 and 5xx
+  pub fn is_transient(&self) -> bool {
+    match self {
+      Error::Network { .. } | Error::Timeout { .. } | Error::Incomplete { .. } => true,
+      Error::Http { status, .. } => {
+        status.is_server_error()
+          || *status == StatusCode::REQUEST_TIMEOUT
+          || *status == StatusCode::TOO_MANY_REQUESTS
+      }
+      _ => false,
+    }
+  }
+
+  /// Whether another mirror may succeed. A 403 or 404 is final for one url,
+  /// but another mirror may still have the file or serve it differently encoded.
+  pub fn is_failover(&self) -> bool {
+    match self {
+      Error::Http { status, .. } => {
+        self.is_transient() || *status == StatusCode::FORBIDDEN || *status == StatusCode::NOT_FOUND
+      }
+      Error::DigestMismatch { .. }
+      | Error::UnsupportedEncoding { .. }
+      | Error::InvalidEncoding { .. } => true,
+      _ => self.is_transient(),
+    }
+  }
+
+  pub fn info(&self) -> ErrorInfo {
+    let mut info = ErrorInfo {
+      code: self.code(),
+      message: self.to_string(),
+      http_status: None,
+      io_kind: None,
+      path: None,
+      url: None,
+      expected: None,
+      actual: None,
+      proxy_stage: None,
+    };
+    match self {
+      Error::Http { status, url } => {
+        info.http_status = Some(status.as_u16() as u32);
+        info.url = Some(url.clone());
+      }
+      Error::Network { url, .. }
+      | Error::Timeout { url }
+      | Error::ContentLengthUnsupported { url }
+      | Error::RemoteChanged { url }
+      | Error::UnsupportedEncoding { url, .. }
+      | Error::InvalidEncoding { url, .. }
+      | Error::InvalidManifest { url, .. } => info.url = Some(url.clone()),
+      Error::Io { kind, path, .. } => {
+        info.io_kind = Some(format!("{:?}", kind));
+        info.path = path.as_ref().map(|p| p.display().to_string());
+      }
+      Error::Incomplete { expected, actual } | Error::LengthMismatch { expected, actual } => {
+        info.expected = Some(expected.to_string());
+        info.actual = Some(actual.to_string());
+      }
+      Error::DigestMismatch { expected, actual } => {
+        info.expected = Some(expected.clone());
+        info.actual = Some(actual.clone());
+      }
+      Error::InvalidArchive { path, .. } => info.path = Some(path.display().to_string()),
+      Error::Proxy { stage, .. } => info.proxy_stage = Some(*stage),
+      _ => {}
+    }
+    info
+  }
+
+  /// A JS `Error` with the message of `self` and the fields of `info()` as properties,
+  /// e.g. `err.code === "Http"` and `err.httpStatus === 404`
+  pub fn into_js_error(self, env: Env) -> napi::Error {
+    let info = self.info();
+    let error = env
+      .create_error(napi::Error::from_reason(info.message.clone()))
+      .and_then(|mut error| {
+        error.set_named_property("code", info.code)?;
+        let fields = [
+          ("ioKind", info.io_kind),
+          ("path", info.path),
+          ("url", info.url),
+          ("expected", info.expected),
+          ("actual", info.actual),
+        ];
+        for (name, value) in fields {
+          if let Some(value) = value {
+            error.set_named_property(name, value)?;
+          }
+        }
+        if let Some(status) = info.http_status {
+          error.set_named_property("httpStatus", status)?;
+        }
+        if let Some(stage) = info.proxy_stage {
+          error.set_named_property("proxyStage", stage)?;
+        }
+        Ok(error)
+      });
+    match error {
+      Ok(error) => error.into_unknown().into(),
+      Err(e) => e,
+    }
+  }
+}
+
+/// Runs `future` as a JS promise that rejects with `Error::into_js_error`
+pub fn promise<T, F>(env: Env, future: F) -> napi::Result<JsObject>
+where
+  T: ToNapiValue + Send + 'static,
+  F: Future<Output = Result<T, Error>> + Send + 'static,
+{
+  env.execute_tokio_future(async move { Ok(future.await) }, |env, result| {
+    result.map_err(|e| e.into_js_error(*env))
+  })
+}
+
+impl From<io::Error> for Error {
+  fn from(error: io::Error) -> Self {
+    Self::Io {
+      kind: error.kind(),
+      path: None,
+      message: error.to_string(),
+    }
+  }
+}
+
+impl From<reqwest::Error> for Error {
+  fn from(error: reqwest::Error) -> Self {
+    let url = error.url().map(|url| url.to_string()).unwrap_or_default();
+    if error.is_builder() {
+      Error::InvalidArgument(error.to_string())
+    } else if error.is_timeout() {
+      Error::Timeout { url }
+    } else if let Some(status) = error.status() {
+      Error::Http { status, url }
+    } else {
+      Error::Network {
+        url,
+        message: error.to_string(),
+      }
+    }
+  }
+}
+
+impl From<zip::result::ZipError> for Error {
+  fn from(error: zip::result::ZipError) -> Self {
+    match error {
+      zip::result::ZipError::Io(error) => error.into(),
+      error => Error::Archive(error.to_string()),
+    }
+  }
+}
+
+impl From<rusqlite::Error> for Error {
+  fn from(error: rusqlite::Error) -> Self {
+    Error::Database(error.to_string())
+  }
+}
+
+/// For errors that can not reach `into_js_error`, the code is a prefix of the message, e.g. `Http: ...`
+impl From<Error> for napi::Error {
+  fn from(error: Error) -> Self {
+    let status = match error {
+      Error::InvalidArgument(_)
+      | Error::Proxy {
+        stage: ProxyStage::Parse,
+        ..
+      } => napi::Status::InvalidArg,
+      Error::Cancelled => napi::Status::Cancelled,
+      _ => napi::Status::GenericFailure,
+    };
+    napi::Error::new(status, format!("{:?}: {}", error.code(), error))
+  }
+}
+
+#[test]
+fn test_error_info() {
+  let error = Error::Http {
+    status: StatusCode::NOT_FOUND,
+    url: "https://example.com/a.zip".into(),
+  };
+  assert!(!error.is_transient());
+  assert!(error.is_failover());
+  let info = error.info();
+  assert_eq!(info.code, ErrorCode::Http);
+  assert_eq!(info.http_status, Some(404));
+
+  let error = Error::io(
+    io::Error::new(io::ErrorKind::NotFound, "missing"),
+    Path::new("/tmp/a"),
+  );
+  assert_eq!(error.info().io_kind.as_deref(), Some("NotFound"));
+  assert_eq!(error.to_string(), "missing: /tmp/a");
+  assert_eq!(
+    napi::Error::from(Error::Cancelled).reason,
+    "Cancelled: cancelled"
+  );
+
+  // The stage is spelled the same in messages and in the details passed to JS
+  let error = Error::proxy(ProxyStage::Tls, "handshake failed");
+  assert_eq!(error.to_string(), "proxy tls failed: handshake failed");
+  assert_eq!(
+    serde_json::to_value(error.info().proxy_stage).unwrap(),
+    ProxyStage::Tls.name()
+  );
+}
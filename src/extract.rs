@@ -0,0 +1,181 @@
+use crate::error::Error;
+use crate::http::DownloadOptions;
+use crate::partial::with_suffix;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use zip::ZipArchive;
+
+/// Entries are unpacked in chunks of this size, progress is reported after each one
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct ExtractOptions {
+  /// Where the archive is downloaded to, defaults to `destDir` with a `.zip` suffix
+  pub archive_path: Option<String>,
+  /// Keep the archive once it is extracted, defaults to false
+  pub keep_archive: Option<bool>,
+  pub download: Option<DownloadOptions>,
+}
+
+/// Default archive location for `dest`, `out/` and `out` both give `out.zip` next to it
+pub fn default_archive_path(dest: &Path) -> Result<PathBuf, Error> {
+  let name = dest
+    .file_name()
+    .ok_or_else(|| Error::InvalidArgument(format!("Invalid destination {}", dest.display())))?;
+  Ok(with_suffix(&dest.with_file_name(name), ".zip"))
+}
+
+fn invalid(archive: &Path, message: impl ToString) -> Error {
+  Error::InvalidArchive {
+    path: archive.to_path_buf(),
+    message: message.to_string(),
+  }
+}
+
+fn unsafe_path(archive: &Path, name: &str) -> Error {
+  invalid(
+    archive,
+    format!("entry {} points outside of the destination", name),
+  )
+}
+
+fn open(archive: &Path) -> Result<ZipArchive<File>, Error> {
+  let file = File::open(archive).map_err(|e| Error::io(e, archive))?;
+  ZipArchive::new(file).map_err(|e| invalid(archive, e))
+}
+
+/// Reads every entry of `archive`, so corrupt data and entries pointing outside
+/// of the destination are found before anything is written. Returns the unpacked size.
+pub fn verify_archive(archive: &Path) -> Result<u64, Error> {
+  let mut zip = open(archive)?;
+  let mut size = 0;
+  for i in 0..zip.len() {
+    let mut entry = zip.by_index(i).map_err(|e| invalid(archive, e))?;
+    if entry.enclosed_name().is_none() {
+      return Err(unsafe_path(archive, entry.name()));
+    }
+    // The CRC of an entry is checked once it is read to the end
+    size += io::copy(&mut entry, &mut io::sink()).map_err(|e| invalid(archive, e))?;
+  }
+  Ok(size)
+}
+
+/// Unpacks `archive` into `dest`, calling `on_progress` with the unpacked total and
+/// the size of the last chunk. Returns the number of files, or `None` once `stop` is true.
+pub fn extract_archive(
+  archive: &Path,
+  dest: &Path,
+  mut on_progress: impl FnMut(u64, u64),
+  stop: impl Fn() -> bool,
+) -> Result<Option<u32>, Error> {
+  let mut zip = open(archive)?;
+  fs::create_dir_all(dest).map_err(|e| Error::io(e, dest))?;
+  let mut buffer = vec![0; CHUNK_SIZE];
+  let mut written = 0;
+  let mut files = 0;
+
+  for i in 0..zip.len() {
+    let mut entry = zip.by_index(i).map_err(|e| invalid(archive, e))?;
+    let path = match entry.enclosed_name() {
+      Some(name) => dest.join(name),
+      None => return Err(unsafe_path(archive, entry.name())),
+    };
+    if entry.is_dir() {
+      fs::create_dir_all(&path).map_err(|e| Error::io(e, &path))?;
+      continue;
+    }
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent).map_err(|e| Error::io(e, parent))?;
+    }
+
+    debug!("[Zip] Extracting {}", path.display());
+    let mut file = File::create(&path).map_err(|e| Error::io(e, &path))?;
+    loop {
+      if stop() {
+        return Ok(None);
+      }
+      let n = entry.read(&mut buffer).map_err(|e| invalid(archive, e))?;
+      if n == 0 {
+        break;
+      }
+      file
+        .write_all(&buffer[..n])
+        .map_err(|e| Error::io(e, &path))?;
+      written += n as u64;
+      on_progress(written, n as u64);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    if let Some(mode) = entry.unix_mode() {
+      use std::os::unix::fs::PermissionsExt;
+      fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777))
+        .map_err(|e| Error::io(e, &path))?;
+    }
+    files += 1;
+  }
+  Ok(Some(files))
+}
+
+#[test]
+fn test_extract_archive() {
+  use zip::write::FileOptions;
+  use zip::ZipWriter;
+
+  let dir = std::env::temp_dir().join(format!("extract-test-{}", std::process::id()));
+  let _ = fs::remove_dir_all(&dir);
+  fs::create_dir_all(&dir).unwrap();
+  let archive = dir.join("archive.zip");
+
+  let mut writer = ZipWriter::new(File::create(&archive).unwrap());
+  writer
+    .add_directory("data/", FileOptions::default())
+    .unwrap();
+  writer
+    .start_file("data/a.txt", FileOptions::default())
+    .unwrap();
+  writer.write_all(b"hello").unwrap();
+  writer.start_file("b.txt", FileOptions::default()).unwrap();
+  writer.write_all(b"world!").unwrap();
+  writer.finish().unwrap();
+
+  assert_eq!(verify_archive(&archive).unwrap(), 11);
+  let dest = dir.join("out");
+  let mut progress = Vec::new();
+  let files = extract_archive(&archive, &dest, |n, _| progress.push(n), || false).unwrap();
+  assert_eq!(files, Some(2));
+  assert_eq!(progress.last(), Some(&11));
+  assert_eq!(fs::read(dest.join("data/a.txt")).unwrap(), b"hello");
+  assert_eq!(
+    extract_archive(&archive, &dir.join("stopped"), |_, _| {}, || true).unwrap(),
+    None
+  );
+
+  let evil = dir.join("evil.zip");
+  let mut writer = ZipWriter::new(File::create(&evil).unwrap());
+  writer
+    .start_file("../evil.txt", FileOptions::default())
+    .unwrap();
+  writer.write_all(b"evil").unwrap();
+  writer.finish().unwrap();
+  assert!(verify_archive(&evil).is_err());
+
+  let corrupt = dir.join("corrupt.zip");
+  fs::write(&corrupt, b"not a zip").unwrap();
+  assert!(matches!(
+    verify_archive(&corrupt),
+    Err(Error::InvalidArchive { .. })
+  ));
+
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_default_archive_path() {
+  let path = |dest| default_archive_path(Path::new(dest)).unwrap();
+  assert_eq!(path("out"), PathBuf::from("out.zip"));
+  assert_eq!(path("out/"), PathBuf::from("out.zip"));
+  assert_eq!(path("/tmp/out//"), PathBuf::from("/tmp/out.zip"));
+  assert!(default_archive_path(Path::new("/")).is_err());
+}
@@ -6,9 +6,22 @@ extern crate napi_derive;
 #[macro_use]
 extern crate log;
 
+mod checksum;
+mod decode;
+mod download_manager;
+mod error;
+mod extract;
 mod http;
+mod manifest;
+#[cfg(test)]
+mod mock_server;
+mod partial;
+mod progress;
 mod proxy;
+mod proxy_config;
 mod sqlite;
+mod throttle;
+mod upload;
 mod zip;
 
 #[cfg(target_os = "windows")]
@@ -0,0 +1,496 @@
+use crate::checksum::{digest_matches, Checksum};
+use crate::error::{Error, ErrorCode, ErrorInfo};
+use crate::http::{DownloadOptions, DownloadProgress, HttpFileDownloader, ProgressSink};
+use crate::partial::{meta_path, part_path};
+use crate::progress::ProgressTracker;
+use futures::stream::{self, StreamExt};
+use reqwest::Url;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Files hashed or downloaded at the same time unless `concurrency` is set
+const DEFAULT_CONCURRENCY: u32 = 4;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+  /// Path relative to the destination, with `/` separators
+  pub path: String,
+  pub size: u64,
+  pub sha256: String,
+  /// Where the file is downloaded from, defaults to `path` relative to the manifest
+  pub url: Option<String>,
+}
+
+/// A manifest is either a list of entries or an object with a `files` list
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Manifest {
+  List(Vec<ManifestEntry>),
+  Object { files: Vec<ManifestEntry> },
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct SyncOptions {
+  /// Number of files hashed or downloaded at the same time, defaults to 4
+  pub concurrency: Option<u32>,
+  /// Delete files in `destDir` that are not in the manifest, defaults to false
+  pub delete_extraneous: Option<bool>,
+  /// Options for every file download, `checksum` is taken from the manifest
+  pub download: Option<DownloadOptions>,
+}
+
+#[napi(object)]
+pub struct SyncFailure {
+  pub path: String,
+  pub error: ErrorInfo,
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct SyncManifestResponse {
+  /// Every file of the manifest is in place
+  pub status: bool,
+  /// Why the sync stopped, e.g. the manifest could not be fetched or it was cancelled
+  pub error: Option<ErrorInfo>,
+  /// Paths that were downloaded
+  pub downloaded: Vec<String>,
+  /// Number of files that were already up to date
+  pub unchanged: u32,
+  /// Paths deleted because they are not in the manifest
+  pub deleted: Vec<String>,
+  /// Files that could not be downloaded
+  pub failed: Vec<SyncFailure>,
+}
+
+/// Byte counts of the downloads in flight, merged into one `progress` stream
+struct Aggregate {
+  done: Vec<u64>,
+  tracker: ProgressTracker,
+}
+
+pub fn parse_manifest(data: &[u8], url: &str) -> Result<Vec<ManifestEntry>, Error> {
+  let invalid = |message: String| Error::InvalidManifest {
+    url: url.to_string(),
+    message,
+  };
+  let entries = match serde_json::from_slice(data).map_err(|e| invalid(e.to_string()))? {
+    Manifest::List(entries) => entries,
+    Manifest::Object { files } => files,
+  };
+  for entry in &entries {
+    if relative_path(&entry.path).is_none() {
+      return Err(invalid(format!(
+        "entry {} points outside of the destination",
+        entry.path
+      )));
+    }
+  }
+  Ok(entries)
+}
+
+/// Converts a manifest path to a relative one, `None` when it could leave the destination
+fn relative_path(path: &str) -> Option<PathBuf> {
+  let path = Path::new(path);
+  let safe = path.components().next().is_some()
+    && path
+      .components()
+      .all(|component| matches!(component, Component::Normal(_)));
+  safe.then(|| path.to_path_buf())
+}
+
+/// Whether the file at `path` has the size and digest listed in `entry`
+async fn is_current(path: &Path, entry: &ManifestEntry) -> bool {
+  match tokio::fs::metadata(path).await {
+    Ok(meta) if meta.is_file() && meta.len() == entry.size => {}
+    _ => return false,
+  }
+  let mut hasher = match Checksum::from_algorithm(None) {
+    Ok(hasher) => hasher,
+    Err(_) => return false,
+  };
+  hasher.update_from_file(path, entry.size).await.is_ok()
+    && digest_matches(&hasher.finalize(), &entry.sha256)
+}
+
+/// Collects the files below `dir` that are not in `keep`
+fn extraneous_files(dir: &Path, keep: &HashSet<PathBuf>, found: &mut Vec<PathBuf>) {
+  let entries = match fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(_) => return,
+  };
+  for entry in entries.flatten() {
+    let path = entry.path();
+    match entry.file_type() {
+      Ok(kind) if kind.is_dir() => extraneous_files(&path, keep, found),
+      Ok(_) if !keep.contains(&path) => found.push(path),
+      _ => {}
+    }
+  }
+}
+
+/// Forwards the events of the download of file `index` to `emit`,
+/// replacing its `progress` events with those of the whole sync
+fn file_sink(
+  emit: Option<ProgressSink>,
+  aggregate: Arc<Mutex<Aggregate>>,
+  index: usize,
+  path: String,
+) -> ProgressSink {
+  Arc::new(move |mut progress: DownloadProgress| {
+    let emit = match &emit {
+      Some(emit) => emit,
+      None => return,
+    };
+    if progress.target != "progress" {
+      progress.path = Some(path.clone());
+      return emit(progress);
+    }
+    let mut aggregate = aggregate.lock().unwrap();
+    let downloaded = progress.downloaded.max(0) as u64;
+    let bytes = downloaded.saturating_sub(aggregate.done[index]);
+    aggregate.done[index] = downloaded;
+    let total = aggregate.done.iter().sum();
+    if let Some(progress) = aggregate.tracker.record(total, bytes) {
+      emit(progress);
+    }
+  })
+}
+
+pub async fn sync_manifest(
+  downloader: &HttpFileDownloader,
+  manifest_url: &str,
+  dest: &Path,
+  options: SyncOptions,
+) -> SyncManifestResponse {
+  let failed = |error: Error| SyncManifestResponse {
+    error: Some(error.info()),
+    ..Default::default()
+  };
+  let base = match Url::parse(manifest_url) {
+    Ok(base) => base,
+    Err(e) => return failed(Error::InvalidArgument(format!("Invalid url: {}", e))),
+  };
+  let entries = match downloader.get_bytes(manifest_url).await {
+    Ok(data) => match parse_manifest(&data, manifest_url) {
+      Ok(entries) => entries,
+      Err(e) => return failed(e),
+    },
+    Err(e) => return failed(e),
+  };
+  info!("[Sync] {} lists {} files", manifest_url, entries.len());
+
+  let concurrency = options.concurrency.unwrap_or(DEFAULT_CONCURRENCY).max(1) as usize;
+  let download = options.download.unwrap_or_default();
+  let emit = downloader.emitter();
+
+  // Local files are hashed first, so only missing or changed ones are downloaded
+  let total = entries.iter().map(|entry| entry.size).sum();
+  let mut tracker = ProgressTracker::new(&download, 0, Some(total));
+  let mut checked = 0;
+  let mut current = vec![false; entries.len()];
+  let mut checks = stream::iter(entries.clone().into_iter().enumerate())
+    .map(|(index, entry)| async move {
+      let path = dest.join(relative_path(&entry.path).unwrap_or_default());
+      (index, is_current(&path, &entry).await)
+    })
+    .buffer_unordered(concurrency);
+  while let Some((index, is_current)) = checks.next().await {
+    current[index] = is_current;
+    checked += entries[index].size;
+    if let (Some(emit), Some(mut progress)) = (&emit, tracker.record(checked, entries[index].size))
+    {
+      progress.target = "hashing";
+      emit(progress);
+    }
+  }
+  drop(checks);
+
+  let pending: Vec<ManifestEntry> = entries
+    .iter()
+    .zip(&current)
+    .filter(|(_, current)| !**current)
+    .map(|(entry, _)| entry.clone())
+    .collect();
+  let unchanged = (entries.len() - pending.len()) as u32;
+  info!(
+    "[Sync] {} files up to date, {} to download",
+    unchanged,
+    pending.len()
+  );
+
+  let total = pending.iter().map(|entry| entry.size).sum();
+  let aggregate = Arc::new(Mutex::new(Aggregate {
+    done: vec![0; pending.len()],
+    tracker: ProgressTracker::new(&download, 0, Some(total)),
+  }));
+  let results: Vec<(ManifestEntry, Result<(), ErrorInfo>)> =
+    stream::iter(pending.into_iter().enumerate())
+      .map(|(index, entry)| {
+        let sink = file_sink(emit.clone(), aggregate.clone(), index, entry.path.clone());
+        let aggregate = aggregate.clone();
+        let emit = emit.clone();
+        let options = DownloadOptions {
+          checksum: Some(entry.sha256.clone()),
+          checksum_algorithm: None,
+          ..download.clone()
+        };
+        let base = &base;
+        async move {
+          if downloader.is_cancelled() {
+            return (entry, Err(Error::Cancelled.info()));
+          }
+          let path = dest.join(relative_path(&entry.path).unwrap_or_default());
+          let url = match base.join(entry.url.as_deref().unwrap_or(&entry.path)) {
+            Ok(url) => url.to_string(),
+            Err(e) => {
+              let error = Error::InvalidManifest {
+                url: base.to_string(),
+                message: format!("invalid url for {}: {}", entry.path, e),
+              };
+              return (entry, Err(error.info()));
+            }
+          };
+          if let Some(parent) = path.parent() {
+            if let Err(e) = tokio::fs::create_dir_all(parent).await {
+              return (entry, Err(Error::io(e, parent).info()));
+            }
+          }
+
+          let response = downloader
+            .with_emitter(sink)
+            .download(url, path.to_string_lossy().into_owned(), Some(options))
+            .await;
+          if !response.status {
+            return (
+              entry,
+              Err(response.error.unwrap_or_else(|| Error::Cancelled.info())),
+            );
+          }
+          aggregate.lock().unwrap().done[index] = entry.size;
+          if let Some(emit) = &emit {
+            emit(DownloadProgress {
+              target: "file",
+              downloaded: entry.size as i64,
+              total: Some(entry.size as i64),
+              path: Some(entry.path.clone()),
+              ..Default::default()
+            });
+          }
+          (entry, Ok(()))
+        }
+      })
+      .buffer_unordered(concurrency)
+      .collect()
+      .await;
+
+  let mut response = SyncManifestResponse {
+    unchanged,
+    ..Default::default()
+  };
+  for (entry, result) in results {
+    match result {
+      Ok(()) => response.downloaded.push(entry.path),
+      Err(error) if error.code == ErrorCode::Cancelled => {}
+      Err(error) => {
+        warn!("[Sync] {} failed: {}", entry.path, error.message);
+        response.failed.push(SyncFailure {
+          path: entry.path,
+          error,
+        });
+      }
+    }
+  }
+  if downloader.is_cancelled() {
+    response.error = Some(Error::Cancelled.info());
+    return response;
+  }
+
+  if options.delete_extraneous.unwrap_or(false) {
+    // Partial downloads of listed files are kept for the next sync
+    let keep: HashSet<PathBuf> = entries
+      .iter()
+      .map(|entry| dest.join(relative_path(&entry.path).unwrap_or_default()))
+      .flat_map(|path| [part_path(&path), meta_path(&path), path])
+      .collect();
+    let mut extraneous = Vec::new();
+    extraneous_files(dest, &keep, &mut extraneous);
+    for path in extraneous {
+      match fs::remove_file(&path) {
+        Ok(()) => {
+          let relative = path.strip_prefix(dest).unwrap_or(&path);
+          let relative = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+          info!("[Sync] Deleted {}", relative);
+          response.deleted.push(relative);
+        }
+        Err(e) => warn!("[Sync] Failed to delete {}: {}", path.display(), e),
+      }
+    }
+  }
+
+  response.status = response.failed.is_empty();
+  response
+}
+
+#[test]
+fn test_parse_manifest() {
+  let data = br#"{"files": [{"path": "bin/app", "size": 3, "sha256": "ab"}]}"#;
+  let entries = parse_manifest(data, "").unwrap();
+  assert_eq!(entries[0].path, "bin/app");
+  assert_eq!(entries[0].url, None);
+
+  let data = br#"[{"path": "a", "size": 1, "sha256": "ab", "url": "https://cdn/a"}]"#;
+  assert_eq!(parse_manifest(data, "").unwrap().len(), 1);
+
+  for path in ["../evil", "/etc/passwd", "a/../../b", ""] {
+    let data = format!(r#"[{{"path": "{}", "size": 1, "sha256": "ab"}}]"#, path);
+    assert!(matches!(
+      parse_manifest(data.as_bytes(), ""),
+      Err(Error::InvalidManifest { .. })
+    ));
+  }
+}
+
+#[cfg(test)]
+fn sha256(data: &[u8]) -> String {
+  let mut hasher = Checksum::from_algorithm(None).unwrap();
+  hasher.update(data);
+  hasher.finalize()
+}
+
+#[tokio::test]
+async fn test_sync_manifest() {
+  use crate::http::{test_dir, test_downloader};
+  use crate::mock_server::{test_data, MockServer, Route};
+
+  let server = MockServer::start().await;
+  let files = [
+    ("a.bin", test_data(3000, 1)),
+    ("dir/b.bin", test_data(2000, 2)),
+    ("c.bin", test_data(1000, 3)),
+  ];
+  let manifest: Vec<_> = files
+    .iter()
+    .map(|(path, data)| {
+      serde_json::json!({ "path": path, "size": data.len(), "sha256": sha256(data) })
+    })
+    .collect();
+  let manifest = serde_json::to_vec(&manifest).unwrap();
+  server.route("/sync/manifest.json", Route::new(&manifest));
+  for (path, data) in &files {
+    server.route(&format!("/sync/{}", path), Route::new(data));
+  }
+  let dest = test_dir("sync");
+  fs::create_dir_all(dest.join("dir")).unwrap();
+  // Changed in place, the length still matches
+  fs::write(dest.join("dir/b.bin"), test_data(2000, 9)).unwrap();
+  fs::write(dest.join("c.bin"), &files[2].1).unwrap();
+  fs::write(dest.join("dir/old.txt"), b"old").unwrap();
+
+  let (downloader, events) = test_downloader();
+  let url = server.url("/sync/manifest.json");
+  let sync = || {
+    let options = SyncOptions {
+      delete_extraneous: Some(true),
+      ..Default::default()
+    };
+    sync_manifest(&downloader, &url, &dest, options)
+  };
+  let response = sync().await;
+  assert!(response.status && response.failed.is_empty());
+  let mut downloaded = response.downloaded.clone();
+  downloaded.sort();
+  assert_eq!(downloaded, ["a.bin", "dir/b.bin"]);
+  assert_eq!(response.unchanged, 1);
+  assert_eq!(response.deleted, ["dir/old.txt"]);
+  for (path, data) in &files {
+    assert_eq!(&fs::read(dest.join(path)).unwrap(), data, "{}", path);
+  }
+  assert!(server.requests("/sync/c.bin").is_empty());
+
+  {
+    let events = events.lock().unwrap();
+    let last = |target| events.iter().rev().find(|e| e.target == target).unwrap();
+    // Every listed file is hashed, only the downloaded ones count towards `progress`
+    assert_eq!(
+      (last("hashing").downloaded, last("hashing").total),
+      (6000, Some(6000))
+    );
+    assert_eq!(
+      (last("progress").downloaded, last("progress").total),
+      (5000, Some(5000))
+    );
+    let progress: Vec<_> = events.iter().filter(|e| e.target == "progress").collect();
+    assert!(progress
+      .windows(2)
+      .all(|pair| pair[0].downloaded <= pair[1].downloaded));
+    let mut files: Vec<_> = events
+      .iter()
+      .filter(|e| e.target == "file")
+      .map(|e| e.path.clone().unwrap())
+      .collect();
+    files.sort();
+    assert_eq!(files, ["a.bin", "dir/b.bin"]);
+  }
+
+  let requests = server.requests("/sync/a.bin").len();
+  let response = sync().await;
+  assert!(response.status && response.downloaded.is_empty());
+  assert_eq!(response.unchanged, 3);
+  assert_eq!(server.requests("/sync/a.bin").len(), requests);
+}
+
+#[tokio::test]
+async fn test_sync_manifest_failures() {
+  use crate::http::{test_dir, test_downloader};
+  use crate::mock_server::{test_data, MockServer, Route};
+
+  let server = MockServer::start().await;
+  let data = test_data(1000, 1);
+  let manifest = serde_json::json!({ "files": [
+    { "path": "ok.bin", "size": 1000, "sha256": sha256(&data) },
+    { "path": "missing.bin", "size": 1000, "sha256": sha256(&data) },
+  ]});
+  server.route(
+    "/manifest.json",
+    Route::new(&serde_json::to_vec(&manifest).unwrap()),
+  );
+  server.route("/ok.bin", Route::new(&data));
+  let dest = test_dir("sync-failures");
+  fs::write(dest.join("extra.txt"), b"extra").unwrap();
+  let (downloader, _) = test_downloader();
+  let options = SyncOptions {
+    download: Some(DownloadOptions {
+      max_retries: Some(0),
+      ..Default::default()
+    }),
+    ..Default::default()
+  };
+
+  let response = sync_manifest(&downloader, &server.url("/manifest.json"), &dest, options).await;
+  assert!(!response.status);
+  assert_eq!(response.downloaded, ["ok.bin"]);
+  assert_eq!(response.failed.len(), 1);
+  assert_eq!(response.failed[0].path, "missing.bin");
+  assert_eq!(response.failed[0].error.code, ErrorCode::Http);
+  assert_eq!(response.failed[0].error.http_status, Some(404));
+  // Extraneous files are kept unless `deleteExtraneous` is set
+  assert!(response.deleted.is_empty());
+  assert!(dest.join("extra.txt").exists());
+
+  let response = sync_manifest(
+    &downloader,
+    &server.url("/none.json"),
+    &dest,
+    SyncOptions::default(),
+  )
+  .await;
+  assert_eq!(response.error.unwrap().code, ErrorCode::Http);
+}
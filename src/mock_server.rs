@@ -0,0 +1,539 @@
+//! Minimal HTTP/1.1 server and proxies on localhost for the downloader and proxy tests
+
+use crate::http::{DownloadProgress, ProgressSink};
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+
+/// How one path is served. After answering a request the route is replaced by `then`.
+#[derive(Clone)]
+pub struct Route {
+  pub body: Vec<u8>,
+  /// Answer `Range` requests with `206`
+  pub ranges: bool,
+  /// `Accept-Ranges` value sent instead of `bytes`, regardless of `ranges`
+  pub accept_ranges: Option<String>,
+  pub content_length: bool,
+  /// Send the body with `Transfer-Encoding: chunked` instead of a `Content-Length`
+  pub chunked: bool,
+  pub etag: Option<String>,
+  pub last_modified: Option<String>,
+  /// Respond with this status and an empty body
+  pub status: Option<u16>,
+  /// Close the connection after this many body bytes
+  pub drop_after: Option<usize>,
+  /// Keep the connection open without sending more after `drop_after` bytes
+  pub stall: bool,
+  /// Extra response headers
+  pub headers: Vec<(String, String)>,
+  pub then: Option<Box<Route>>,
+}
+
+impl Route {
+  pub fn new(body: &[u8]) -> Self {
+    Self {
+      body: body.to_vec(),
+      ranges: true,
+      accept_ranges: None,
+      content_length: true,
+      chunked: false,
+      etag: None,
+      last_modified: None,
+      status: None,
+      drop_after: None,
+      stall: false,
+      headers: Vec::new(),
+      then: None,
+    }
+  }
+
+  pub fn status(status: u16) -> Self {
+    Self {
+      status: Some(status),
+      ..Self::new(b"")
+    }
+  }
+
+  pub fn etag(mut self, etag: &str) -> Self {
+    self.etag = Some(etag.to_string());
+    self
+  }
+
+  pub fn no_ranges(mut self) -> Self {
+    self.ranges = false;
+    self
+  }
+
+  pub fn accept_ranges(mut self, value: &str) -> Self {
+    self.accept_ranges = Some(value.to_string());
+    self
+  }
+
+  pub fn no_content_length(mut self) -> Self {
+    self.content_length = false;
+    self
+  }
+
+  pub fn chunked(mut self) -> Self {
+    self.content_length = false;
+    self.chunked = true;
+    self
+  }
+
+  pub fn drop_after(mut self, bytes: usize) -> Self {
+    self.drop_after = Some(bytes);
+    self
+  }
+
+  pub fn stall_after(mut self, bytes: usize) -> Self {
+    self.drop_after = Some(bytes);
+    self.stall = true;
+    self
+  }
+
+  pub fn header(mut self, name: &str, value: &str) -> Self {
+    self.headers.push((name.to_string(), value.to_string()));
+    self
+  }
+
+  pub fn then(mut self, next: Route) -> Self {
+    self.then = Some(Box::new(next));
+    self
+  }
+}
+
+/// A request as received, header names are lower case
+#[derive(Clone)]
+pub struct Request {
+  pub method: String,
+  pub path: String,
+  pub headers: HashMap<String, String>,
+  pub body: Vec<u8>,
+}
+
+#[derive(Default)]
+struct State {
+  routes: HashMap<String, Route>,
+  requests: Vec<Request>,
+}
+
+pub struct MockServer {
+  port: u16,
+  tls: bool,
+  state: Arc<Mutex<State>>,
+}
+
+impl MockServer {
+  pub async fn start() -> Self {
+    Self::start_with(None).await
+  }
+
+  /// Serves HTTPS with the self-signed certificate in `fixtures`
+  pub async fn start_tls() -> Self {
+    Self::start_with(Some(tls_acceptor())).await
+  }
+
+  async fn start_with(acceptor: Option<TlsAcceptor>) -> Self {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let state = Arc::new(Mutex::new(State::default()));
+    let shared = state.clone();
+    let tls = acceptor.is_some();
+    tokio::spawn(async move {
+      while let Ok((socket, _)) = listener.accept().await {
+        let (acceptor, state) = (acceptor.clone(), shared.clone());
+        tokio::spawn(async move {
+          match acceptor {
+            Some(acceptor) => {
+              if let Ok(socket) = acceptor.accept(socket).await {
+                handle(socket, state).await;
+              }
+            }
+            None => handle(socket, state).await,
+          }
+        });
+      }
+    });
+    Self { port, tls, state }
+  }
+
+  pub fn url(&self, path: &str) -> String {
+    let scheme = if self.tls { "https" } else { "http" };
+    format!("{}://127.0.0.1:{}{}", scheme, self.port, path)
+  }
+
+  pub fn route(&self, path: &str, route: Route) {
+    self
+      .state
+      .lock()
+      .unwrap()
+      .routes
+      .insert(path.to_string(), route);
+  }
+
+  /// Headers of the requests received for `path`, oldest first
+  pub fn requests(&self, path: &str) -> Vec<HashMap<String, String>> {
+    self
+      .received(path)
+      .into_iter()
+      .map(|request| request.headers)
+      .collect()
+  }
+
+  /// Requests received for `path` with their method and body, oldest first
+  pub fn received(&self, path: &str) -> Vec<Request> {
+    let state = self.state.lock().unwrap();
+    state
+      .requests
+      .iter()
+      .filter(|request| request.path == path)
+      .cloned()
+      .collect()
+  }
+}
+
+async fn handle(mut socket: impl AsyncRead + AsyncWrite + Unpin, state: Arc<Mutex<State>>) {
+  let mut data = Vec::new();
+  let mut buf = [0; 4096];
+  let head_len = loop {
+    if let Some(i) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+      break i + 4;
+    }
+    match socket.read(&mut buf).await {
+      Ok(0) | Err(_) => return,
+      Ok(n) => data.extend_from_slice(&buf[..n]),
+    }
+  };
+  let head = String::from_utf8_lossy(&data[..head_len]).to_string();
+  let mut lines = head.split("\r\n");
+  let mut request_line = lines.next().unwrap_or_default().split(' ');
+  let method = request_line.next().unwrap_or("GET").to_string();
+  let path = request_line.next().unwrap_or("/").to_string();
+  let headers: HashMap<String, String> = lines
+    .take_while(|line| !line.is_empty())
+    .filter_map(|line| line.split_once(':'))
+    .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+    .collect();
+  // Uploads always send a Content-Length
+  let body_len = headers
+    .get("content-length")
+    .and_then(|len| len.parse::<usize>().ok())
+    .unwrap_or(0);
+  while data.len() < head_len + body_len {
+    match socket.read(&mut buf).await {
+      Ok(0) | Err(_) => return,
+      Ok(n) => data.extend_from_slice(&buf[..n]),
+    }
+  }
+
+  let route = {
+    let mut state = state.lock().unwrap();
+    let route = state.routes.get(&path).cloned();
+    if let Some(next) = route.as_ref().and_then(|route| route.then.clone()) {
+      state.routes.insert(path.clone(), *next);
+    }
+    state.requests.push(Request {
+      method,
+      path,
+      headers: headers.clone(),
+      body: data[head_len..].to_vec(),
+    });
+    route
+  };
+  let route = route.unwrap_or_else(|| Route::status(404));
+  let (head, body) = respond(&route, &headers);
+  let body = match route.drop_after {
+    Some(n) => &body[..n.min(body.len())],
+    None => &body[..],
+  };
+  let _ = socket.write_all(head.as_bytes()).await;
+  let _ = socket.write_all(body).await;
+  if route.stall {
+    let _ = socket.flush().await;
+    std::future::pending::<()>().await;
+  }
+  let _ = socket.shutdown().await;
+}
+
+/// Builds the response head and the body to send for a request with `headers`
+fn respond(route: &Route, headers: &HashMap<String, String>) -> (String, Vec<u8>) {
+  let extra: String = route
+    .headers
+    .iter()
+    .map(|(name, value)| format!("{}: {}\r\n", name, value))
+    .collect();
+  if let Some(status) = route.status {
+    let head = format!(
+      "HTTP/1.1 {} Mock\r\nContent-Length: 0\r\n{}Connection: close\r\n\r\n",
+      status, extra
+    );
+    return (head, Vec::new());
+  }
+
+  let len = route.body.len();
+  // A range is only served while `If-Range` still names the current version
+  let current = match headers.get("if-range") {
+    Some(validator) => {
+      route.etag.as_ref() == Some(validator) || route.last_modified.as_ref() == Some(validator)
+    }
+    None => true,
+  };
+  let range = headers
+    .get("range")
+    .filter(|_| route.ranges && current && len > 0)
+    .and_then(|range| range.strip_prefix("bytes="))
+    .and_then(|range| range.split_once('-'))
+    .and_then(|(start, end)| {
+      let start: usize = start.parse().ok()?;
+      let end = end.parse().unwrap_or(len.saturating_sub(1)).min(len - 1);
+      (start <= end).then_some((start, end))
+    });
+
+  let mut head = match range {
+    Some((start, end)) => format!(
+      "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n",
+      start, end, len
+    ),
+    None => "HTTP/1.1 200 OK\r\n".to_string(),
+  };
+  let mut body = match range {
+    Some((start, end)) => route.body[start..=end].to_vec(),
+    None => route.body.clone(),
+  };
+  if route.chunked {
+    head += "Transfer-Encoding: chunked\r\n";
+    let mut chunked = Vec::new();
+    for chunk in body.chunks(4096) {
+      chunked.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+      chunked.extend_from_slice(chunk);
+      chunked.extend_from_slice(b"\r\n");
+    }
+    chunked.extend_from_slice(b"0\r\n\r\n");
+    body = chunked;
+  }
+  if route.content_length {
+    head += &format!("Content-Length: {}\r\n", body.len());
+  }
+  match &route.accept_ranges {
+    Some(value) => head += &format!("Accept-Ranges: {}\r\n", value),
+    None if route.ranges => head += "Accept-Ranges: bytes\r\n",
+    None => {}
+  }
+  if let Some(etag) = &route.etag {
+    head += &format!("ETag: {}\r\n", etag);
+  }
+  if let Some(last_modified) = &route.last_modified {
+    head += &format!("Last-Modified: {}\r\n", last_modified);
+  }
+  head += &extra;
+  head += "Connection: close\r\n\r\n";
+  (head, body)
+}
+
+/// A sink that keeps every event for later inspection
+pub fn recorder() -> (ProgressSink, Arc<Mutex<Vec<DownloadProgress>>>) {
+  let events = Arc::new(Mutex::new(Vec::new()));
+  let sink = events.clone();
+  (
+    Arc::new(move |progress| sink.lock().unwrap().push(progress)),
+    events,
+  )
+}
+
+/// Deterministic test data that differs for every `seed` and does not repeat,
+/// so bytes written at a wrong offset are noticed
+pub fn test_data(len: usize, seed: u8) -> Vec<u8> {
+  (0..len)
+    .map(|i| ((i.wrapping_mul(2_654_435_761) >> 13) as u8) ^ seed)
+    .collect()
+}
+
+/// A server that answers the first bytes of every connection with `reply` and hangs up.
+/// An empty `reply` leaves the client waiting until it gives up.
+pub async fn reply_server(reply: &'static [u8]) -> u16 {
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let port = listener.local_addr().unwrap().port();
+  tokio::spawn(async move {
+    while let Ok((mut socket, _)) = listener.accept().await {
+      tokio::spawn(async move {
+        let mut buf = [0; 4096];
+        while let Ok(1..) = socket.read(&mut buf).await {
+          if !reply.is_empty() {
+            let _ = socket.write_all(reply).await;
+            return;
+          }
+        }
+      });
+    }
+  });
+  port
+}
+
+fn tls_acceptor() -> TlsAcceptor {
+  let config = ServerConfig::builder()
+    .with_safe_defaults()
+    .with_no_client_auth()
+    .with_single_cert(
+      vec![Certificate(
+        include_bytes!("../fixtures/proxy-cert.der").to_vec(),
+      )],
+      PrivateKey(include_bytes!("../fixtures/proxy-key.der").to_vec()),
+    )
+    .unwrap();
+  TlsAcceptor::from(Arc::new(config))
+}
+
+/// Protocol spoken by a `MockProxy`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+  /// `CONNECT` and forwarded requests
+  Http,
+  /// SOCKS4 and SOCKS4a
+  Socks4,
+}
+
+/// A connection as the proxy received it
+#[derive(Clone, Debug)]
+pub struct ProxiedRequest {
+  /// `host:port` the client asked for, host names as sent by the client
+  pub target: String,
+  /// SOCKS4 user id or `Proxy-Authorization` header
+  pub auth: Option<String>,
+}
+
+/// A proxy that connects to any target, optionally behind TLS with the certificate in `fixtures`
+pub struct MockProxy {
+  port: u16,
+  requests: Arc<Mutex<Vec<ProxiedRequest>>>,
+}
+
+impl MockProxy {
+  pub async fn start(kind: ProxyKind, tls: bool) -> Self {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let acceptor = tls.then(tls_acceptor);
+    let shared = requests.clone();
+    tokio::spawn(async move {
+      while let Ok((socket, _)) = listener.accept().await {
+        let (acceptor, requests) = (acceptor.clone(), shared.clone());
+        tokio::spawn(async move {
+          match acceptor {
+            Some(acceptor) => {
+              if let Ok(socket) = acceptor.accept(socket).await {
+                proxy(socket, kind, requests).await;
+              }
+            }
+            None => proxy(socket, kind, requests).await,
+          }
+        });
+      }
+    });
+    Self { port, requests }
+  }
+
+  pub fn port(&self) -> u16 {
+    self.port
+  }
+
+  pub fn requests(&self) -> Vec<ProxiedRequest> {
+    self.requests.lock().unwrap().clone()
+  }
+}
+
+async fn read_until(socket: &mut (impl AsyncRead + Unpin), end: &[u8]) -> Option<Vec<u8>> {
+  let mut data = Vec::new();
+  while !data.ends_with(end) {
+    data.push(socket.read_u8().await.ok()?);
+  }
+  Some(data)
+}
+
+async fn proxy(
+  mut socket: impl AsyncRead + AsyncWrite + Unpin,
+  kind: ProxyKind,
+  requests: Arc<Mutex<Vec<ProxiedRequest>>>,
+) {
+  let (request, forward) = match kind {
+    ProxyKind::Http => {
+      let head = match read_until(&mut socket, b"\r\n\r\n").await {
+        Some(head) => String::from_utf8_lossy(&head).to_string(),
+        None => return,
+      };
+      let mut words = head.split(' ');
+      let (method, target) = (words.next().unwrap_or(""), words.next().unwrap_or(""));
+      let auth = head
+        .split("\r\n")
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("proxy-authorization"))
+        .map(|(_, value)| value.trim().to_string());
+      let target = match method {
+        "CONNECT" => target.to_string(),
+        _ => {
+          let url = reqwest::Url::parse(target).unwrap();
+          format!(
+            "{}:{}",
+            url.host_str().unwrap(),
+            url.port_or_known_default().unwrap()
+          )
+        }
+      };
+      // Forwarded requests are passed on as they are, the mock server routes by full url
+      let forward = (method != "CONNECT").then(|| head.into_bytes());
+      (ProxiedRequest { target, auth }, forward)
+    }
+    ProxyKind::Socks4 => {
+      let mut header = [0; 8];
+      if socket.read_exact(&mut header).await.is_err() {
+        return;
+      }
+      let port = u16::from_be_bytes([header[2], header[3]]);
+      let ip = std::net::Ipv4Addr::new(header[4], header[5], header[6], header[7]);
+      let user_id = match read_until(&mut socket, b"\0").await {
+        Some(id) => String::from_utf8_lossy(&id[..id.len() - 1]).to_string(),
+        None => return,
+      };
+      // SOCKS4a sends `0.0.0.x` followed by the host name
+      let host = match ip.octets() {
+        [0, 0, 0, x] if x != 0 => match read_until(&mut socket, b"\0").await {
+          Some(host) => String::from_utf8_lossy(&host[..host.len() - 1]).to_string(),
+          None => return,
+        },
+        _ => ip.to_string(),
+      };
+      let request = ProxiedRequest {
+        target: format!("{}:{}", host, port),
+        auth: Some(user_id).filter(|id| !id.is_empty()),
+      };
+      (request, None)
+    }
+  };
+  requests.lock().unwrap().push(request.clone());
+
+  let (host, port) = request.target.rsplit_once(':').unwrap();
+  let host = host.trim_start_matches('[').trim_end_matches(']');
+  let upstream = TcpStream::connect((host, port.parse::<u16>().unwrap())).await;
+  let reply: &[u8] = match (kind, &upstream) {
+    (ProxyKind::Http, Ok(_)) => b"HTTP/1.1 200 Connection Established\r\n\r\n",
+    (ProxyKind::Http, Err(_)) => b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n",
+    (ProxyKind::Socks4, Ok(_)) => &[0, 0x5a, 0, 0, 0, 0, 0, 0],
+    (ProxyKind::Socks4, Err(_)) => &[0, 0x5b, 0, 0, 0, 0, 0, 0],
+  };
+  let mut upstream = match upstream {
+    Ok(upstream) => upstream,
+    Err(_) => {
+      let _ = socket.write_all(reply).await;
+      return;
+    }
+  };
+  let sent = match &forward {
+    Some(head) => upstream.write_all(head).await,
+    None => socket.write_all(reply).await,
+  };
+  if sent.is_ok() {
+    let _ = tokio::io::copy_bidirectional(&mut socket, &mut upstream).await;
+  }
+}
@@ -0,0 +1,156 @@
+use crate::error::Error;
+use reqwest::header::{HeaderMap, ETAG, LAST_MODIFIED};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Sidecar metadata of an unfinished download, stored next to its `.part` file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PartialDownload {
+  pub url: String,
+  pub etag: Option<String>,
+  pub last_modified: Option<String>,
+  pub content_length: u64,
+  /// `false` while the `.part` file may contain holes, e.g. during a segmented download
+  pub resumable: bool,
+  /// Length of the file at `path` after a decoded download, whose metadata is kept
+  /// because its `content_length` can not be compared with the file on disk
+  #[serde(default)]
+  pub decoded_length: Option<u64>,
+}
+
+impl PartialDownload {
+  pub fn new(url: &str, headers: &HeaderMap, content_length: u64) -> Self {
+    let header = |name| {
+      headers
+        .get(name)
+        .and_then(|v: &reqwest::header::HeaderValue| v.to_str().ok())
+        .map(str::to_string)
+    };
+    Self {
+      url: url.to_string(),
+      etag: header(ETAG),
+      last_modified: header(LAST_MODIFIED),
+      content_length,
+      resumable: true,
+      decoded_length: None,
+    }
+  }
+
+  /// Whether the bytes recorded by `self` belong to the file described by `current`.
+  /// Mirrors are trusted to serve the same file when they advertise the same length
+  /// and ETag, their modification dates usually differ.
+  pub fn matches(&self, current: &PartialDownload) -> bool {
+    let same = |a: &Option<String>, b: &Option<String>| match (a, b) {
+      (Some(a), Some(b)) => a == b,
+      _ => true,
+    };
+    self.resumable
+      && self.content_length == current.content_length
+      && same(&self.etag, &current.etag)
+      && (self.url != current.url || same(&self.last_modified, &current.last_modified))
+  }
+
+  /// Whether the decoded file of `file_length` bytes recorded by `self` is still the
+  /// one described by `current`. Without a validator the server file can not be told apart.
+  pub fn is_decoded(&self, current: &PartialDownload, file_length: u64) -> bool {
+    self.decoded_length == Some(file_length)
+      && self.content_length == current.content_length
+      && (self.etag.is_some() || self.last_modified.is_some())
+      && self.etag == current.etag
+      && self.last_modified == current.last_modified
+  }
+
+  pub async fn load(path: &Path) -> Option<Self> {
+    let data = tokio::fs::read(meta_path(path)).await.ok()?;
+    match serde_json::from_slice(&data) {
+      Ok(meta) => Some(meta),
+      Err(e) => {
+        warn!("[File] Ignoring unreadable download metadata: {}", e);
+        None
+      }
+    }
+  }
+
+  pub async fn save(&self, path: &Path) -> Result<(), Error> {
+    let path = meta_path(path);
+    let data = serde_json::to_vec(self).map_err(io::Error::from)?;
+    tokio::fs::write(&path, data)
+      .await
+      .map_err(|e| Error::io(e, &path))
+  }
+
+  /// Removes the `.part` file of `path` and its metadata
+  pub async fn discard(path: &Path) {
+    let _ = tokio::fs::remove_file(part_path(path)).await;
+    let _ = tokio::fs::remove_file(meta_path(path)).await;
+  }
+}
+
+/// Appends `suffix` to the file name of `path`
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+  let mut name = OsString::from(path.as_os_str());
+  name.push(suffix);
+  PathBuf::from(name)
+}
+
+/// File the data of `path` is written to until the download is verified
+pub fn part_path(path: &Path) -> PathBuf {
+  with_suffix(path, ".part")
+}
+
+pub fn meta_path(path: &Path) -> PathBuf {
+  with_suffix(path, ".part.json")
+}
+
+#[test]
+fn test_partial_download_matches() {
+  let mut headers = HeaderMap::new();
+  headers.insert(ETAG, "\"abc\"".parse().unwrap());
+  let stored = PartialDownload::new("https://example.com/a.zip", &headers, 100);
+  assert_eq!(stored.etag.as_deref(), Some("\"abc\""));
+
+  let mut current = stored.clone();
+  assert!(stored.matches(&current));
+  current.last_modified = Some("Wed, 21 Oct 2015 07:28:00 GMT".into());
+  assert!(stored.matches(&current));
+  current.etag = Some("\"def\"".into());
+  assert!(!stored.matches(&current));
+
+  let current = PartialDownload::new("https://example.com/a.zip", &HeaderMap::new(), 101);
+  assert!(!stored.matches(&current));
+  let mut mirror = PartialDownload::new("https://mirror.example.com/a.zip", &headers, 100);
+  mirror.last_modified = Some("Wed, 21 Oct 2015 07:28:00 GMT".into());
+  assert!(stored.matches(&mirror));
+  mirror.etag = Some("\"def\"".into());
+  assert!(!stored.matches(&mirror));
+  assert!(!PartialDownload {
+    resumable: false,
+    ..stored.clone()
+  }
+  .matches(&stored));
+
+  let decoded = PartialDownload {
+    resumable: false,
+    decoded_length: Some(250),
+    ..stored.clone()
+  };
+  assert!(decoded.is_decoded(&stored, 250));
+  assert!(!decoded.is_decoded(&stored, 251));
+  assert!(!decoded.is_decoded(&current, 250));
+  assert!(!PartialDownload {
+    etag: None,
+    ..decoded.clone()
+  }
+  .is_decoded(
+    &PartialDownload::new("https://example.com/a.zip", &HeaderMap::new(), 100),
+    250
+  ));
+
+  assert_eq!(
+    part_path(Path::new("/tmp/core.zip")),
+    PathBuf::from("/tmp/core.zip.part")
+  );
+}
@@ -0,0 +1,145 @@
+use crate::http::{DownloadOptions, DownloadProgress};
+use crate::throttle::RateMeter;
+use std::time::{Duration, Instant};
+
+/// Progress is reported at this interval unless configured otherwise
+const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Turns a stream of received chunks into rate-limited `progress` events
+/// carrying speed, ETA and elapsed time
+pub struct ProgressTracker {
+  started: Instant,
+  /// Bytes that were already on disk when the transfer started
+  offset: u64,
+  total: Option<u64>,
+  meter: RateMeter,
+  rate: f64,
+  interval: Option<Duration>,
+  step: Option<f64>,
+  last_emit: Option<Instant>,
+  last_percent: f64,
+}
+
+impl ProgressTracker {
+  pub fn new(options: &DownloadOptions, offset: u64, total: Option<u64>) -> Self {
+    Self::with_settings(
+      options.progress_interval_ms,
+      options.progress_step,
+      offset,
+      total,
+    )
+  }
+
+  /// Emits every `interval_ms` and every `step` percent, every 100ms when neither is set
+  pub fn with_settings(
+    interval_ms: Option<u32>,
+    step: Option<f64>,
+    offset: u64,
+    total: Option<u64>,
+  ) -> Self {
+    let interval = interval_ms.map(|ms| Duration::from_millis(ms as u64));
+    let step = step.filter(|step| *step > 0.0);
+
+    Self {
+      started: Instant::now(),
+      offset,
+      total,
+      meter: RateMeter::new(),
+      rate: 0.0,
+      interval: match (interval, step) {
+        (None, None) => Some(DEFAULT_PROGRESS_INTERVAL),
+        (interval, _) => interval,
+      },
+      step,
+      last_emit: None,
+      last_percent: 0.0,
+    }
+  }
+
+  fn percent(&self, downloaded: u64) -> f64 {
+    match self.total {
+      Some(total) if total > 0 => downloaded as f64 * 100.0 / total as f64,
+      _ => 0.0,
+    }
+  }
+
+  /// Records a chunk of `bytes`, returns an event when one is due
+  pub fn record(&mut self, downloaded: u64, bytes: u64) -> Option<DownloadProgress> {
+    self.rate = self.meter.record(bytes);
+
+    let interval_due = match (self.interval, self.last_emit) {
+      (Some(_), None) => true,
+      (Some(interval), Some(last)) => last.elapsed() >= interval,
+      (None, _) => false,
+    };
+    let step_due = match self.step {
+      Some(step) => self.percent(downloaded) - self.last_percent >= step,
+      None => false,
+    };
+    let finished = self.total == Some(downloaded);
+
+    if interval_due || step_due || finished {
+      Some(self.snapshot(downloaded))
+    } else {
+      None
+    }
+  }
+
+  /// Builds an event for `downloaded` bytes regardless of the interval
+  pub fn snapshot(&mut self, downloaded: u64) -> DownloadProgress {
+    self.last_emit = Some(Instant::now());
+    self.last_percent = self.percent(downloaded);
+
+    let elapsed = self.started.elapsed();
+    let average_rate =
+      downloaded.saturating_sub(self.offset) as f64 / elapsed.as_secs_f64().max(0.001);
+    let eta_ms = match self.total {
+      Some(total) if self.rate > 0.0 => {
+        Some((total.saturating_sub(downloaded) as f64 / self.rate * 1000.0) as i64)
+      }
+      _ => None,
+    };
+
+    DownloadProgress {
+      target: "progress",
+      downloaded: downloaded as i64,
+      total: self.total.map(|total| total as i64),
+      rate: Some(self.rate),
+      average_rate: Some(average_rate),
+      eta_ms,
+      elapsed_ms: Some(elapsed.as_millis() as i64),
+      ..Default::default()
+    }
+  }
+}
+
+#[test]
+fn test_progress_tracker_step() {
+  let options = DownloadOptions {
+    progress_step: Some(10.0),
+    ..Default::default()
+  };
+  let mut tracker = ProgressTracker::new(&options, 0, Some(1000));
+  let emitted: Vec<u64> = (1..=100)
+    .filter_map(|i| tracker.record(i * 10, 10))
+    .map(|progress| progress.downloaded as u64)
+    .collect();
+  assert_eq!(
+    emitted,
+    vec![100, 200, 300, 400, 500, 600, 700, 800, 900, 1000]
+  );
+}
+
+#[test]
+fn test_progress_tracker_interval() {
+  let options = DownloadOptions {
+    progress_interval_ms: Some(60_000),
+    ..Default::default()
+  };
+  let mut tracker = ProgressTracker::new(&options, 500, Some(1000));
+  assert!(tracker.record(600, 100).is_some());
+  assert!(tracker.record(700, 100).is_none());
+  let last = tracker.record(1000, 300).unwrap();
+  assert_eq!(last.eta_ms, Some(0));
+  assert!(last.average_rate.unwrap() > 0.0);
+}
@@ -1,5 +1,7 @@
 use std::{path::Path, str::FromStr};
 
+use crate::error::{promise, Error};
+use napi::{Env, JsObject};
 use reqwest::Url;
 use rusqlite::Connection;
 use tokio::{fs, io::AsyncWriteExt};
@@ -22,15 +24,23 @@ pub struct LoginCreationParams {
   pub password_field: String,
 }
 
-#[napi]
-async fn sqlite_add_login_password(
+#[napi(ts_return_type = "Promise<LoginData>")]
+fn sqlite_add_login_password(
+  env: Env,
   filepath: String,
   login_params: LoginCreationParams,
-) -> napi::Result<LoginData> {
+) -> napi::Result<JsObject> {
+  promise(env, add_login_password(filepath, login_params))
+}
+
+async fn add_login_password(
+  filepath: String,
+  login_params: LoginCreationParams,
+) -> Result<LoginData, Error> {
   let db_path = Path::new(&filepath);
   if let Err(e) = tokio::fs::metadata(db_path).await {
     error!("File does not exists {}", e);
-    return Err(napi::Error::from_reason("File does not exists".to_string()));
+    return Err(Error::io(e, db_path));
   }
 
   let connection = Connection::open(db_path);
@@ -40,7 +50,7 @@ async fn sqlite_add_login_password(
         Ok(query) => query,
         Err(e) => {
             error!("Error while preparing query: {}", e);
-            return Err(napi::Error::from_reason(format!("Error while preparing query: {}", e)));
+            return Err(Error::Database(format!("Error while preparing query: {}", e)));
         }
     };
 
@@ -56,8 +66,7 @@ async fn sqlite_add_login_password(
             if username_value.is_ok() && password_value.is_ok() {
               let u = username_value.unwrap();
               let p = password_value.unwrap();
-              let putf =
-                String::from_utf8(p).map_err(|e| napi::Error::from_reason(e.to_string()))?;
+              let putf = String::from_utf8(p).map_err(|e| Error::Database(e.to_string()))?;
 
               debug!("Found login for {}: {} {}", &login_params.url, u, putf);
               return if u == login_params.username && putf == login_params.password {
@@ -106,11 +115,12 @@ async fn sqlite_add_login_password(
             ) VALUES (?, ?, ?, ?, ?, ?, '', ?, '13298985082883522', 0, 0, 0, 0, '', '', '', '',\
              0, 0, '', '13298985082883522', '', '')";
             let url_parsed = Url::from_str(&login_params.url)
-              .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+              .map_err(|e| Error::InvalidArgument(e.to_string()))?;
+            let host = url_parsed.host_str().ok_or_else(|| {
+              Error::InvalidArgument(format!("{} has no host", login_params.url))
+            })?;
 
-            let mut statement = connection
-              .prepare(query)
-              .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+            let mut statement = connection.prepare(query).map_err(Error::from)?;
             let result = statement.execute([
               &login_params.url,
               &login_params.url,
@@ -121,7 +131,7 @@ async fn sqlite_add_login_password(
               &format!(
                 "{}://{}/",
                 &url_parsed.scheme(),
-                &url_parsed.host_str().unwrap()
+                host
               ),
             ]);
 
@@ -138,7 +148,7 @@ async fn sqlite_add_login_password(
               }
               Err(e) => {
                 error!("Error while inserting new login: {}", e);
-                Err(napi::Error::from_reason(format!("{}", e)))
+                Err(Error::from(e))
               }
             };
           }
@@ -147,16 +157,13 @@ async fn sqlite_add_login_password(
       }
       Err(e) => {
         error!("Unable to query database: {}", e);
-        return Err(napi::Error::from_reason(format!(
-          "Unable to query database: {}",
-          e
-        )));
+        return Err(Error::Database(format!("Unable to query database: {}", e)));
       }
     }
   } else {
     let e = connection.err();
     error!("Unable to open database, error: {:?}", e);
-    return Err(napi::Error::from_reason(format!(
+    return Err(Error::Database(format!(
       "Unable to open database, error: {:?}",
       e
     )));
@@ -169,8 +176,12 @@ async fn sqlite_add_login_password(
 }
 
 #[allow(unused)]
-#[napi]
-async fn create_sqlite_login_database(path: String) -> napi::Result<bool> {
+#[napi(ts_return_type = "Promise<boolean>")]
+fn create_sqlite_login_database(env: Env, path: String) -> napi::Result<JsObject> {
+  promise(env, create_login_database(path))
+}
+
+async fn create_login_database(path: String) -> Result<bool, Error> {
   let mut file = fs::OpenOptions::new()
     .create(true)
     .append(false)
@@ -187,11 +198,28 @@ async fn create_sqlite_login_database(path: String) -> napi::Result<bool> {
 #[tokio::test]
 async fn test_sqlite_create_database() {
   let path = "/home/pq/database".into();
-  let result = create_sqlite_login_database(path).await;
+  let result = create_login_database(path).await;
 
   println!("{:?}", result);
 }
 
+#[tokio::test]
+async fn test_sqlite_add_login_without_host() {
+  let path = std::env::temp_dir().join(format!("login-data-{}", std::process::id()));
+  create_login_database(path.display().to_string())
+    .await
+    .unwrap();
+  let params = LoginCreationParams {
+    url: "mailto:admin@example.com".into(),
+    username: "admin".into(),
+    password: "admin".into(),
+    username_field: "email".into(),
+    password_field: "password".into(),
+  };
+  let result = add_login_password(path.display().to_string(), params).await;
+  assert!(matches!(result, Err(Error::InvalidArgument(_))));
+}
+
 #[tokio::test]
 async fn test_sqlite_add_coinlist_login_password() {
   pretty_env_logger::init();
@@ -204,7 +232,7 @@ async fn test_sqlite_add_coinlist_login_password() {
     username_field: "user[email]".into(),
     password_field: "user[password]".into(),
   };
-  let result = sqlite_add_login_password(filepath.to_string(), params).await;
+  let result = add_login_password(filepath.to_string(), params).await;
 
   println!("{:?}", result);
 }
@@ -221,7 +249,7 @@ async fn test_sqlite_add_facebook_login_password() {
     username_field: "email".into(),
     password_field: "pass".into(),
   };
-  let result = sqlite_add_login_password(filepath.to_string(), params).await;
+  let result = add_login_password(filepath.to_string(), params).await;
 
   println!("{:?}", result);
 }
@@ -238,7 +266,7 @@ async fn test_sqlite_add_business_facebook_login_password() {
     username_field: "email".into(),
     password_field: "pass".into(),
   };
-  let result = sqlite_add_login_password(filepath.to_string(), params).await;
+  let result = add_login_password(filepath.to_string(), params).await;
 
   println!("{:?}", result);
 }
@@ -255,7 +283,7 @@ async fn test_sqlite_add_ads_tiktok_login_password() {
     username_field: "email".into(),
     password_field: "password".into(),
   };
-  let result = sqlite_add_login_password(filepath.to_string(), params).await;
+  let result = add_login_password(filepath.to_string(), params).await;
 
   println!("{:?}", result);
 }
@@ -272,7 +300,7 @@ async fn test_sqlite_add_google_login_password() {
     username_field: "identifier".into(),
     password_field: "password".into(),
   };
-  let result = sqlite_add_login_password(filepath.to_string(), params).await;
+  let result = add_login_password(filepath.to_string(), params).await;
 
   println!("{:?}", result);
 }
@@ -0,0 +1,130 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Budget shared by every download in the process
+pub static GLOBAL_LIMITER: RateLimiter = RateLimiter::new();
+
+/// Token bucket limiting throughput to a number of bytes per second.
+/// A limit of `0` means unlimited. The limit can be changed at any time
+/// and applies to the next consumed chunk.
+#[derive(Default)]
+pub struct RateLimiter {
+  limit: AtomicU64,
+  bucket: Mutex<Bucket>,
+}
+
+#[derive(Default)]
+struct Bucket {
+  /// Bytes that may be consumed right away, negative while in debt
+  tokens: f64,
+  last: Option<Instant>,
+}
+
+impl RateLimiter {
+  pub const fn new() -> Self {
+    Self {
+      limit: AtomicU64::new(0),
+      bucket: Mutex::new(Bucket {
+        tokens: 0.0,
+        last: None,
+      }),
+    }
+  }
+
+  pub fn set_limit(&self, bytes_per_second: u64) {
+    self.limit.store(bytes_per_second, Ordering::SeqCst);
+  }
+
+  pub fn limit(&self) -> u64 {
+    self.limit.load(Ordering::SeqCst)
+  }
+
+  /// Accounts `bytes` and returns how long the caller has to wait to stay under the limit
+  fn take(&self, bytes: u64) -> Duration {
+    let limit = self.limit() as f64;
+    let mut bucket = self.bucket.lock().unwrap();
+    let now = Instant::now();
+    if limit == 0.0 {
+      bucket.tokens = 0.0;
+      bucket.last = Some(now);
+      return Duration::ZERO;
+    }
+
+    let elapsed = bucket
+      .last
+      .map(|last| now.duration_since(last).as_secs_f64())
+      .unwrap_or(0.0);
+    // Allow at most one second of burst after an idle period
+    bucket.tokens = (bucket.tokens + elapsed * limit).min(limit) - bytes as f64;
+    bucket.last = Some(now);
+
+    if bucket.tokens < 0.0 {
+      Duration::from_secs_f64(-bucket.tokens / limit)
+    } else {
+      Duration::ZERO
+    }
+  }
+
+  /// Waits until `bytes` fit into the budget
+  pub async fn consume(&self, bytes: u64) {
+    let wait = self.take(bytes);
+    if !wait.is_zero() {
+      tokio::time::sleep(wait).await;
+    }
+  }
+}
+
+/// Measures throughput over windows of about one second
+pub struct RateMeter {
+  window_start: Instant,
+  window_bytes: u64,
+  rate: Option<f64>,
+}
+
+impl Default for RateMeter {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl RateMeter {
+  const WINDOW: Duration = Duration::from_secs(1);
+
+  pub fn new() -> Self {
+    Self {
+      window_start: Instant::now(),
+      window_bytes: 0,
+      rate: None,
+    }
+  }
+
+  /// Records `bytes` and returns the current rate in bytes per second
+  pub fn record(&mut self, bytes: u64) -> f64 {
+    self.window_bytes += bytes;
+    let elapsed = self.window_start.elapsed();
+    let current = self.window_bytes as f64 / elapsed.as_secs_f64().max(0.001);
+    if elapsed >= Self::WINDOW {
+      self.rate = Some(current);
+      self.window_start = Instant::now();
+      self.window_bytes = 0;
+    }
+    self.rate.unwrap_or(current)
+  }
+}
+
+#[test]
+fn test_rate_limiter_debt() {
+  let limiter = RateLimiter::new();
+  assert_eq!(limiter.take(1_000_000), Duration::ZERO);
+
+  limiter.set_limit(1000);
+  limiter.take(0);
+  let wait = limiter.take(500);
+  assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+  let wait = limiter.take(500);
+  assert!(wait > Duration::from_millis(950) && wait <= Duration::from_millis(1000));
+
+  limiter.set_limit(0);
+  assert_eq!(limiter.take(1_000_000), Duration::ZERO);
+}
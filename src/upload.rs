@@ -0,0 +1,661 @@
+use crate::error::{promise, Error, ErrorInfo};
+use crate::http::{
+  build_client, progress_sink, send, DownloadProgress, HttpClientOptions, ProgressSink,
+  TransferControl,
+};
+use crate::progress::ProgressTracker;
+use crate::throttle::{RateLimiter, GLOBAL_LIMITER};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use napi::{Env, JsFunction, JsObject};
+use reqwest::header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE, LOCATION};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+const TUS_VERSION: &str = "1.0.0";
+
+/// Bytes sent per PATCH request of a resumable upload
+const DEFAULT_CHUNK_SIZE: i64 = 8 * 1024 * 1024;
+
+/// The file is read in blocks of this size, progress is reported after each one
+const READ_BLOCK_SIZE: u64 = 64 * 1024;
+
+#[napi]
+#[derive(Clone)]
+pub struct HttpFileUploader {
+  emitter: Option<ProgressSink>,
+  control: TransferControl,
+  client: reqwest::Client,
+  rate_limiter: Arc<RateLimiter>,
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct UploadOptions {
+  /// `PUT` (default) sends the raw file, `POST` sends it as `multipart/form-data`
+  pub method: Option<String>,
+  /// Multipart field of the file, defaults to `file`
+  pub field_name: Option<String>,
+  /// File name sent with the multipart field and as tus metadata, defaults to the name on disk
+  pub file_name: Option<String>,
+  /// Defaults to `application/octet-stream`
+  pub content_type: Option<String>,
+  /// Extra multipart fields sent before the file
+  pub fields: Option<HashMap<String, String>>,
+  /// Upload with the tus protocol, `url` is then the creation endpoint
+  pub resumable: Option<bool>,
+  /// Url of an unfinished resumable upload, continued from the offset the server reports
+  pub upload_url: Option<String>,
+  /// Bytes per request of a resumable upload, defaults to 8 MiB
+  pub chunk_size: Option<i64>,
+  /// Minimum time between `progress` events, defaults to 100 unless `progressStep` is set
+  pub progress_interval_ms: Option<u32>,
+  /// Emit `progress` every time this many more percent are uploaded
+  pub progress_step: Option<f64>,
+}
+
+#[napi(object)]
+pub struct HttpFileUploaderResponse {
+  pub status: bool,
+  /// Status code of the last response, `0` when none was received
+  pub status_code: u32,
+  /// Why the upload failed, set when `status` is false
+  pub error: Option<ErrorInfo>,
+  /// Body of the last response
+  pub body: String,
+  /// Url of a resumable upload, pass it as `uploadUrl` to continue after a failure
+  pub upload_url: Option<String>,
+}
+
+/// Source of a request body: `len` bytes of `path` starting at `offset`
+struct FileRange<'a> {
+  path: &'a Path,
+  offset: u64,
+  len: u64,
+}
+
+#[allow(unused)]
+#[napi]
+impl HttpFileUploader {
+  #[napi(constructor)]
+  pub fn new(
+    env: Env,
+    emitter: Option<JsFunction>,
+    options: Option<HttpClientOptions>,
+  ) -> napi::Result<Self> {
+    let options = options.unwrap_or_default();
+    let emitter = match emitter {
+      Some(func) => Some(progress_sink(func)?),
+      None => None,
+    };
+
+    Ok(Self {
+      emitter,
+      control: TransferControl::default(),
+      client: build_client(&options).map_err(|e| e.into_js_error(env))?,
+      rate_limiter: Arc::new(RateLimiter::new()),
+    })
+  }
+
+  /// Limits this uploader to `bytes_per_second`, `0` removes the limit
+  #[napi]
+  pub fn set_rate_limit(&self, bytes_per_second: i64) {
+    info!("[Upload] Rate limit: {} B/s", bytes_per_second);
+    self.rate_limiter.set_limit(bytes_per_second.max(0) as u64);
+  }
+
+  /// Aborts the request in flight. A resumable upload can be continued later with `uploadUrl`.
+  #[napi]
+  pub fn cancel(&self) {
+    info!("[Upload] Upload cancelled");
+    self.control.cancel();
+  }
+
+  /// Stops sending data until `resume` or `cancel`, the connection is kept open
+  #[napi]
+  pub fn pause(&self) -> bool {
+    info!("[Upload] Upload paused");
+    self.control.pause()
+  }
+
+  #[napi]
+  pub fn resume(&self) -> bool {
+    info!("[Upload] Upload resumed");
+    self.control.resume()
+  }
+
+  /// Streams `file` to `url`. Emits `progress` events where `downloaded` counts the bytes
+  /// sent, and a `created` event with the `url` of a new resumable upload.
+  /// Controls are reset before the promise is returned, so a `cancel` or
+  /// `pause` right after this call applies to the new upload.
+  #[napi(ts_return_type = "Promise<HttpFileUploaderResponse>")]
+  pub fn upload_file(
+    &self,
+    env: Env,
+    url: String,
+    file: String,
+    options: Option<UploadOptions>,
+  ) -> napi::Result<JsObject> {
+    self.control.reset();
+    let uploader = self.clone();
+    promise(env, async move {
+      Ok::<_, Error>(uploader.upload(url, file, options).await)
+    })
+  }
+}
+
+impl HttpFileUploader {
+  async fn upload(
+    &self,
+    url: String,
+    file: String,
+    options: Option<UploadOptions>,
+  ) -> HttpFileUploaderResponse {
+    let options = options.unwrap_or_default();
+    let path = PathBuf::from(file);
+    let total = match tokio::fs::metadata(&path).await {
+      Ok(meta) => meta.len(),
+      Err(e) => return failed_response(&Error::io(e, &path), None),
+    };
+    let file_name = match &options.file_name {
+      Some(name) => name.clone(),
+      None => path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default(),
+    };
+
+    // Kept when the upload fails after creating it, so it can be continued
+    let mut upload_url = options.upload_url.clone();
+    let upload = async {
+      if options.resumable.unwrap_or(false) || upload_url.is_some() {
+        let created = match &upload_url {
+          Some(upload_url) => upload_url.clone(),
+          None => {
+            let created = self.create_upload(&url, total, &file_name).await?;
+            upload_url = Some(created.clone());
+            created
+          }
+        };
+        self
+          .upload_resumable(&created, &path, total, &options)
+          .await
+      } else {
+        self
+          .upload_whole(&url, &path, total, &file_name, &options)
+          .await
+      }
+    };
+    // Dropping the upload aborts the request in flight
+    let result = tokio::select! {
+      result = upload => result,
+      _ = self.control.cancelled() => Err(Error::Cancelled),
+    };
+    match result {
+      Ok(response) => response,
+      // The stream may fail before the cancellation itself is noticed
+      Err(_) if self.control.is_cancelled() => failed_response(&Error::Cancelled, upload_url),
+      Err(e) => {
+        error!("[Upload] Upload of {} failed: {}", path.display(), e);
+        failed_response(&e, upload_url)
+      }
+    }
+  }
+
+  /// Sends the file in a single PUT, or a multipart POST
+  async fn upload_whole(
+    &self,
+    url: &str,
+    path: &Path,
+    total: u64,
+    file_name: &str,
+    options: &UploadOptions,
+  ) -> Result<HttpFileUploaderResponse, Error> {
+    let method = options.method.as_deref().unwrap_or("PUT").to_uppercase();
+    let content_type = options
+      .content_type
+      .as_deref()
+      .unwrap_or("application/octet-stream");
+    let range = FileRange {
+      path,
+      offset: 0,
+      len: total,
+    };
+    let tracker = self.tracker(options, 0, total);
+    info!("[Upload] {} {} to {}", method, path.display(), url);
+
+    let request = match method.as_str() {
+      "PUT" => {
+        let request = self.client.put(url).header(CONTENT_TYPE, content_type);
+        self
+          .with_body(request, &range, &tracker, Bytes::new(), Bytes::new())
+          .await?
+      }
+      "POST" => {
+        let boundary = format!(
+          "{:016x}{:016x}",
+          RandomState::new().build_hasher().finish(),
+          RandomState::new().build_hasher().finish()
+        );
+        let mut head = String::new();
+        for (name, value) in options.fields.iter().flatten() {
+          head.push_str(&format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+            boundary,
+            quote(name),
+            value
+          ));
+        }
+        head.push_str(&format!(
+          "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+          boundary,
+          quote(options.field_name.as_deref().unwrap_or("file")),
+          quote(file_name),
+          content_type
+        ));
+        let tail = format!("\r\n--{}--\r\n", boundary);
+        let request = self.client.post(url).header(
+          CONTENT_TYPE,
+          format!("multipart/form-data; boundary={}", boundary),
+        );
+        self
+          .with_body(request, &range, &tracker, head.into(), tail.into())
+          .await?
+      }
+      _ => {
+        return Err(Error::InvalidArgument(format!(
+          "Unsupported upload method {}",
+          method
+        )))
+      }
+    };
+
+    let response = send(request).await?;
+    let status_code = response.status().as_u16() as u32;
+    let body = response.text().await?;
+    Ok(HttpFileUploaderResponse {
+      status: true,
+      status_code,
+      error: None,
+      body,
+      upload_url: None,
+    })
+  }
+
+  /// Creates a tus upload at the creation endpoint `url`, returns the url of the upload
+  async fn create_upload(&self, url: &str, total: u64, file_name: &str) -> Result<String, Error> {
+    let response = send(
+      self
+        .client
+        .post(url)
+        .header("Tus-Resumable", TUS_VERSION)
+        .header("Upload-Length", total)
+        .header(
+          "Upload-Metadata",
+          format!("filename {}", base64::encode(file_name)),
+        ),
+    )
+    .await?;
+    let location = response
+      .headers()
+      .get(LOCATION)
+      .and_then(|v| v.to_str().ok())
+      .ok_or_else(|| Error::Network {
+        url: url.to_string(),
+        message: "no upload url in the response".to_string(),
+      })?;
+    // The location may be relative to the creation endpoint
+    let upload_url = response
+      .url()
+      .join(location)
+      .map_err(|e| Error::Network {
+        url: url.to_string(),
+        message: format!("invalid upload url {}: {}", location, e),
+      })?
+      .to_string();
+    self.emit(DownloadProgress {
+      target: "created",
+      url: Some(upload_url.clone()),
+      ..Default::default()
+    });
+    Ok(upload_url)
+  }
+
+  /// Sends the file to the tus upload at `upload_url` in chunks,
+  /// starting from the offset the server reports
+  async fn upload_resumable(
+    &self,
+    upload_url: &str,
+    path: &Path,
+    total: u64,
+    options: &UploadOptions,
+  ) -> Result<HttpFileUploaderResponse, Error> {
+    let upload_url = upload_url.to_string();
+    info!(
+      "[Upload] Resumable upload of {} to {}",
+      path.display(),
+      upload_url
+    );
+
+    let response = send(
+      self
+        .client
+        .head(&upload_url)
+        .header("Tus-Resumable", TUS_VERSION),
+    )
+    .await?;
+    let mut offset = upload_offset(response.headers(), &upload_url)?;
+    let mut status_code = response.status().as_u16() as u32;
+    let mut body = String::new();
+    if offset > 0 {
+      info!("[Upload] Server already has {} of {} bytes", offset, total);
+    }
+    let chunk_size = options.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE).max(1) as u64;
+    let tracker = self.tracker(options, offset, total);
+
+    while offset < total {
+      let range = FileRange {
+        path,
+        offset,
+        len: chunk_size.min(total - offset),
+      };
+      let request = self
+        .client
+        .patch(&upload_url)
+        .header("Tus-Resumable", TUS_VERSION)
+        .header("Upload-Offset", offset)
+        .header(CONTENT_TYPE, "application/offset+octet-stream");
+      let request = self
+        .with_body(request, &range, &tracker, Bytes::new(), Bytes::new())
+        .await?;
+      let response = send(request).await?;
+      let next = upload_offset(response.headers(), &upload_url)?;
+      if next <= offset {
+        return Err(Error::Network {
+          url: upload_url,
+          message: format!("server did not accept data at offset {}", offset),
+        });
+      }
+      offset = next;
+      status_code = response.status().as_u16() as u32;
+      body = response.text().await.unwrap_or_default();
+    }
+
+    Ok(HttpFileUploaderResponse {
+      status: true,
+      status_code,
+      error: None,
+      body,
+      upload_url: Some(upload_url),
+    })
+  }
+
+  fn emit(&self, progress: DownloadProgress) {
+    if let Some(emit) = &self.emitter {
+      emit(progress);
+    }
+  }
+
+  fn tracker(
+    &self,
+    options: &UploadOptions,
+    offset: u64,
+    total: u64,
+  ) -> Arc<Mutex<ProgressTracker>> {
+    Arc::new(Mutex::new(ProgressTracker::with_settings(
+      options.progress_interval_ms,
+      options.progress_step,
+      offset,
+      Some(total),
+    )))
+  }
+
+  /// Streams `range` between `head` and `tail` as the body of `request`,
+  /// reporting progress and honouring pause and the rate limits
+  async fn with_body(
+    &self,
+    request: reqwest::RequestBuilder,
+    range: &FileRange<'_>,
+    tracker: &Arc<Mutex<ProgressTracker>>,
+    head: Bytes,
+    tail: Bytes,
+  ) -> Result<reqwest::RequestBuilder, Error> {
+    let mut file = File::open(range.path)
+      .await
+      .map_err(|e| Error::io(e, range.path))?;
+    file.seek(SeekFrom::Start(range.offset)).await?;
+    // With a known length the body is not sent chunked
+    let length = head.len() as u64 + range.len + tail.len() as u64;
+    let state = BodyState {
+      file,
+      remaining: range.len,
+      sent: range.offset,
+      tracker: tracker.clone(),
+      emitter: self.emitter.clone(),
+      control: self.control.clone(),
+      rate_limiter: self.rate_limiter.clone(),
+    };
+    let stream = futures_util::stream::iter([Ok(head)])
+      .chain(body_stream(state))
+      .chain(futures_util::stream::iter([Ok(tail)]));
+    Ok(
+      request
+        .header(CONTENT_LENGTH, length)
+        .body(reqwest::Body::wrap_stream(stream)),
+    )
+  }
+}
+
+struct BodyState {
+  file: File,
+  remaining: u64,
+  sent: u64,
+  tracker: Arc<Mutex<ProgressTracker>>,
+  emitter: Option<ProgressSink>,
+  control: TransferControl,
+  rate_limiter: Arc<RateLimiter>,
+}
+
+fn body_stream(state: BodyState) -> impl Stream<Item = io::Result<Bytes>> {
+  futures::stream::unfold(state, |mut state| async move {
+    if state.remaining == 0 {
+      return None;
+    }
+    if !state.control.resumed().await {
+      state.remaining = 0;
+      return Some((
+        Err(io::Error::new(
+          io::ErrorKind::Interrupted,
+          "Upload cancelled",
+        )),
+        state,
+      ));
+    }
+
+    let mut buffer = vec![0; READ_BLOCK_SIZE.min(state.remaining) as usize];
+    let n = match state.file.read(&mut buffer).await {
+      Ok(0) => {
+        state.remaining = 0;
+        return Some((
+          Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "File was truncated during upload",
+          )),
+          state,
+        ));
+      }
+      Ok(n) => n,
+      Err(e) => {
+        state.remaining = 0;
+        return Some((Err(e), state));
+      }
+    };
+    buffer.truncate(n);
+    state.remaining -= n as u64;
+    state.sent += n as u64;
+
+    state.rate_limiter.consume(n as u64).await;
+    GLOBAL_LIMITER.consume(n as u64).await;
+    if let Some(emit) = &state.emitter {
+      let progress = state.tracker.lock().unwrap().record(state.sent, n as u64);
+      if let Some(progress) = progress {
+        emit(progress);
+      }
+    }
+    Some((Ok(Bytes::from(buffer)), state))
+  })
+}
+
+fn upload_offset(headers: &HeaderMap, url: &str) -> Result<u64, Error> {
+  headers
+    .get("Upload-Offset")
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.parse().ok())
+    .ok_or_else(|| Error::Network {
+      url: url.to_string(),
+      message: "no valid Upload-Offset in the response".to_string(),
+    })
+}
+
+/// Escapes a value for a quoted `Content-Disposition` parameter
+fn quote(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn failed_response(error: &Error, upload_url: Option<String>) -> HttpFileUploaderResponse {
+  let info = error.info();
+  HttpFileUploaderResponse {
+    status: false,
+    status_code: info.http_status.unwrap_or(0),
+    error: Some(info),
+    body: String::new(),
+    upload_url,
+  }
+}
+
+#[test]
+fn test_quote() {
+  assert_eq!(quote("profile \"a\".zip"), "profile \\\"a\\\".zip");
+}
+
+#[cfg(test)]
+fn test_uploader(name: &str, data: &[u8]) -> (HttpFileUploader, String) {
+  let dir = std::env::temp_dir().join(format!("upload-test-{}-{}", name, std::process::id()));
+  let _ = std::fs::remove_dir_all(&dir);
+  std::fs::create_dir_all(&dir).unwrap();
+  let path = dir.join("profile.zip");
+  std::fs::write(&path, data).unwrap();
+  let uploader = HttpFileUploader {
+    emitter: None,
+    control: TransferControl::default(),
+    client: reqwest::Client::builder().no_proxy().build().unwrap(),
+    rate_limiter: Arc::new(RateLimiter::new()),
+  };
+  (uploader, path.display().to_string())
+}
+
+#[tokio::test]
+async fn test_upload_put_and_multipart() {
+  use crate::mock_server::{test_data, MockServer, Route};
+
+  let server = MockServer::start().await;
+  server.route("/put", Route::new(b"stored"));
+  server.route("/form", Route::new(b"stored"));
+  let data = test_data(200_000, 1);
+  let (uploader, file) = test_uploader("whole", &data);
+
+  let response = uploader
+    .upload(server.url("/put"), file.clone(), None)
+    .await;
+  assert!(response.status);
+  assert_eq!(
+    (response.status_code, response.body.as_str()),
+    (200, "stored")
+  );
+  let request = &server.received("/put")[0];
+  assert_eq!(request.method, "PUT");
+  assert_eq!(request.body, data);
+
+  let options = UploadOptions {
+    method: Some("post".to_string()),
+    field_name: Some("archive".to_string()),
+    fields: Some(HashMap::from([("profile".to_string(), "42".to_string())])),
+    ..Default::default()
+  };
+  let response = uploader
+    .upload(server.url("/form"), file, Some(options))
+    .await;
+  assert!(response.status);
+  let request = &server.received("/form")[0];
+  assert_eq!(request.method, "POST");
+  let boundary = request.headers["content-type"]
+    .strip_prefix("multipart/form-data; boundary=")
+    .unwrap();
+  let head = format!(
+    "--{b}\r\nContent-Disposition: form-data; name=\"profile\"\r\n\r\n42\r\n\
+     --{b}\r\nContent-Disposition: form-data; name=\"archive\"; filename=\"profile.zip\"\r\n\
+     Content-Type: application/octet-stream\r\n\r\n",
+    b = boundary
+  );
+  let tail = format!("\r\n--{}--\r\n", boundary);
+  assert_eq!(
+    request.body,
+    [head.as_bytes(), &data, tail.as_bytes()].concat()
+  );
+}
+
+#[tokio::test]
+async fn test_upload_resumable() {
+  use crate::mock_server::{test_data, MockServer, Route};
+
+  let server = MockServer::start().await;
+  let data = test_data(1000, 1);
+  let (uploader, file) = test_uploader("tus", &data);
+
+  // The server already holds the first 400 bytes of an earlier attempt
+  server.route(
+    "/files/1",
+    Route::status(200)
+      .header("Upload-Offset", "400")
+      .then(Route::status(204).header("Upload-Offset", "1000")),
+  );
+  let options = UploadOptions {
+    upload_url: Some(server.url("/files/1")),
+    ..Default::default()
+  };
+  let response = uploader
+    .upload(server.url("/files"), file.clone(), Some(options))
+    .await;
+  assert!(response.status);
+  assert_eq!(response.upload_url, Some(server.url("/files/1")));
+  let requests = server.received("/files/1");
+  assert_eq!(requests[0].method, "HEAD");
+  let patch = &requests[1];
+  assert_eq!(patch.method, "PATCH");
+  assert_eq!(patch.headers["upload-offset"], "400");
+  assert_eq!(patch.body, &data[400..]);
+
+  // A failure after creating the upload returns its url to continue with
+  server.route("/files", Route::status(201).header("Location", "/files/2"));
+  server.route("/files/2", Route::status(500));
+  let options = UploadOptions {
+    resumable: Some(true),
+    ..Default::default()
+  };
+  let response = uploader
+    .upload(server.url("/files"), file, Some(options))
+    .await;
+  assert!(!response.status);
+  assert_eq!(response.status_code, 500);
+  assert_eq!(response.upload_url, Some(server.url("/files/2")));
+  assert_eq!(
+    server.received("/files")[0].headers["upload-length"],
+    "1000"
+  );
+}
@@ -1,81 +1,54 @@
 use glob::glob;
 use std::fs::File;
 use std::io::{Read, Write};
-use std::path::{Path, PathBuf};
+use std::path::Path;
 
-use napi::Result;
-use napi::Status::GenericFailure;
+use crate::error::{promise, Error};
+use napi::{Env, JsObject, Result};
 use zip::write::FileOptions;
 use zip::{CompressionMethod, ZipWriter};
 
-#[derive(thiserror::Error, Debug)]
-pub enum ZipError<'a> {
-  #[error("unable to write file {0}")]
-  WriteError(PathBuf),
-
-  #[error("unable to read file {0}")]
-  FileReadError(&'a PathBuf),
-
-  #[error("global error occured")]
-  GlobError(),
-}
-
-impl<'a> From<ZipError<'a>> for napi::Error {
-  fn from(e: ZipError) -> Self {
-    napi::Error::new(GenericFailure, format!("{:?}", e))
-  }
-}
-
 #[allow(unused)]
-#[napi]
-async fn archivate_folder(
+#[napi(ts_return_type = "Promise<boolean>")]
+fn archivate_folder(
+  env: Env,
   output_file: String,
   input_dir: String,
   file_list: Vec<String>,
-) -> Result<bool> {
-  let handle = tokio::task::spawn(async move {
+) -> Result<JsObject> {
+  let archive = async move {
     let output_path = Path::new(&output_file);
     let input_dir = Path::new(&input_dir);
 
-    let file = File::create(&output_path)?;
+    let file = File::create(output_path).map_err(|e| Error::io(e, output_path))?;
     let mut zip_writer = ZipWriter::new(file);
     let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
 
     for file in file_list {
       if file.contains('*') {
-        for entry in glob(input_dir.join(file).to_str().unwrap()).unwrap() {
+        let pattern = input_dir.join(file);
+        let entries = glob(&pattern.to_string_lossy())
+          .map_err(|e| Error::InvalidArgument(format!("Invalid pattern: {}", e)))?;
+        for entry in entries {
           match entry {
             Ok(pathb) => {
               let pathn = pathb
                 .strip_prefix(input_dir)
-                .map_err(|e| napi::Error::new(GenericFailure, format!("Error: {:?}", &e)))?;
+                .map_err(|e| Error::Archive(format!("{:?}", e)))?;
               debug!("adding: {:?}", pathn);
               if pathb.is_dir() {
-                zip_writer
-                  .add_directory(path_to_string(pathn), options)
-                  .map_err(|e| napi::Error::new(GenericFailure, format!("Error: {:?}", &e)))?;
+                zip_writer.add_directory(path_to_string(pathn), options)?;
                 continue;
               }
-              let m = zip_writer.start_file(path_to_string(pathn), options);
-              if m.is_err() {
-                return Err(ZipError::WriteError(pathn.to_path_buf()).into());
-              }
-
-              let data = get_bytes_by_filename(&pathb);
-              if data.is_err() {
-                return Err(ZipError::FileReadError(&pathn.to_path_buf()).into());
-              }
-
-              if zip_writer
+              zip_writer.start_file(path_to_string(pathn), options)?;
+              zip_writer
                 .write_all(get_bytes_by_filename(&pathb)?.as_slice())
-                .is_err()
-              {
-                return Err(ZipError::WriteError(pathn.to_path_buf()).into());
-              };
+                .map_err(|e| Error::io(e, output_path))?;
             }
             Err(e) => {
               error!("error: {}", e);
-              return Err(ZipError::GlobError().into());
+              let path = e.path().to_path_buf();
+              return Err(Error::io(e.into_error(), &path));
             }
           }
         }
@@ -83,18 +56,21 @@ async fn archivate_folder(
         if !input_dir.join(&file).is_file() {
           continue;
         }
-        zip_writer.start_file(&file, options).unwrap();
-        zip_writer.write_all(get_bytes_by_filename(&input_dir.join(&file))?.as_slice())?;
+        zip_writer.start_file(&file, options)?;
+        zip_writer
+          .write_all(get_bytes_by_filename(&input_dir.join(&file))?.as_slice())
+          .map_err(|e| Error::io(e, output_path))?;
       }
     }
 
-    zip_writer.finish().unwrap();
-    Ok(true)
+    zip_writer.finish()?;
+    Ok::<_, Error>(true)
+  };
+  promise(env, async move {
+    tokio::task::spawn(archive)
+      .await
+      .map_err(|e| Error::Archive(e.to_string()))?
   })
-  .await
-  .map_err(|e| napi::Error::new(GenericFailure, format!("{}", e)))?;
-
-  handle
 }
 
 fn path_to_string(path: &std::path::Path) -> String {
@@ -110,14 +86,15 @@ fn path_to_string(path: &std::path::Path) -> String {
   path_str
 }
 
-fn get_bytes_by_filename(path: &Path) -> Result<Vec<u8>> {
-  let mut file = File::open(&path)?;
-
-  let meta = std::fs::metadata(&path)?;
-  let mut buffer = vec![0; meta.len() as usize];
-  file.read_exact(&mut buffer)?;
-
-  Ok(buffer)
+fn get_bytes_by_filename(path: &Path) -> std::result::Result<Vec<u8>, Error> {
+  let read = || {
+    let mut file = File::open(path)?;
+    let meta = std::fs::metadata(path)?;
+    let mut buffer = vec![0; meta.len() as usize];
+    file.read_exact(&mut buffer)?;
+    Ok(buffer)
+  };
+  read().map_err(|e| Error::io(e, path))
 }
 
 #[test]
@@ -126,4 +103,3 @@ fn test_glob() {
     println!("{:?}", entry);
   }
 }
-